@@ -0,0 +1,445 @@
+
+extern crate fuse;
+extern crate flate2;
+
+use std::io;
+use std::cmp;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::fs as hostfs;
+use std::path::Component;
+use self::fuse::FileType;
+use self::flate2::read::{GzDecoder, DeflateDecoder};
+
+use fs::*;
+use common::*;
+use persist::read_exact;
+
+/* st_mode file type bits, as stored in tar headers and zip attributes */
+const S_IFMT:  u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+const TAR_BLOCK: u64 = 512;
+
+const ZIP_LOCAL_HEADER: u32 = 0x04034b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP_END_OF_CENTRAL_DIR: u32 = 0x06054b50;
+/* "version made by" host system whose external attributes hold st_mode */
+const ZIP_HOST_UNIX: u16 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    // Guessed from the file name: .tar, .tar.gz or .tgz, .zip
+    pub fn of(path: &Path) -> Option<Format> {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_lowercase(),
+            None => return None,
+        };
+        if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Storage {
+    Stored(u64),            // offset in the (decompressed) archive stream
+    Deflated(u64, u64),     // zip: offset and length of the compressed data
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub path: PathBuf,              // relative, without . or .. components
+    pub kind: FileType,
+    pub perm: Perm,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub size: u64,
+    pub target: Option<String>,     // symlink target
+    storage: Storage,
+}
+
+// Index of an archive file. Entry contents are not kept in memory;
+// reader() goes back to the file for them.
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    format: Format,
+    entries: Vec<Entry>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> io::Result<Archive> {
+        let format = try!(Format::of(path).ok_or(invalid("unknown archive format")));
+        let mut archive = Archive { path: path.to_owned(), format: format, entries: Vec::new() };
+        archive.entries = match format {
+            Format::Tar | Format::TarGz => try!(read_tar(&mut try!(archive.stream()))),
+            Format::Zip => try!(archive.read_zip()),
+        };
+        Ok(archive)
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+    pub fn format(&self) -> Format { self.format }
+    pub fn entries(&self) -> &[Entry] { &self.entries }
+
+    // Contents of `entry` from `offset` on. Stored data in a plain file
+    // is reached with a seek; compressed data is decoded from the start
+    // of its stream and skipped up to `offset`.
+    pub fn reader(&self, entry: &Entry, offset: u64) -> io::Result<Box<Read>> {
+        let offset = cmp::min(offset, entry.size);
+        let rest = entry.size - offset;
+        match (self.format, entry.storage) {
+            (Format::TarGz, Storage::Stored(start)) => {
+                let mut r = try!(self.stream());
+                try!(skip(&mut r, start + offset));
+                Ok(Box::new(r.take(rest)))
+            },
+            (_, Storage::Stored(start)) => {
+                let mut file = try!(hostfs::File::open(&self.path));
+                try!(file.seek(SeekFrom::Start(start + offset)));
+                Ok(Box::new(io::BufReader::new(file).take(rest)))
+            },
+            (_, Storage::Deflated(start, len)) => {
+                let mut file = try!(hostfs::File::open(&self.path));
+                try!(file.seek(SeekFrom::Start(start)));
+                let mut r = DeflateDecoder::new(io::BufReader::new(file.take(len)));
+                try!(skip(&mut r, offset));
+                Ok(Box::new(r.take(rest)))
+            },
+        }
+    }
+
+    // The archive bytes, decompressed for .tar.gz
+    fn stream(&self) -> io::Result<Box<Read>> {
+        let file = io::BufReader::new(try!(hostfs::File::open(&self.path)));
+        match self.format {
+            Format::TarGz => Ok(Box::new(try!(GzDecoder::new(file)))),
+            _ => Ok(Box::new(file)),
+        }
+    }
+
+    // Entries come from the central directory at the end of the file.
+    // Zip64 archives are not supported.
+    fn read_zip(&self) -> io::Result<Vec<Entry>> {
+        let mut file = try!(hostfs::File::open(&self.path));
+        let len = try!(file.seek(SeekFrom::End(0)));
+
+        // The end record is followed by a comment of up to 64 KiB
+        let tail_len = cmp::min(len, 22 + 0xffff);
+        try!(file.seek(SeekFrom::Start(len - tail_len)));
+        let mut tail = vec![0; tail_len as usize];
+        try!(read_exact(&mut file, &mut tail));
+        let end = try!((0 .. (tail.len() + 1).saturating_sub(22)).rev()
+            .find(|&i| le32(&tail[i..]) == ZIP_END_OF_CENTRAL_DIR)
+            .ok_or(invalid("not a zip archive")));
+        let count = le16(&tail[end + 10..]);
+        let cd_size = le32(&tail[end + 12..]);
+        let cd_offset = le32(&tail[end + 16..]);
+        if cd_offset == 0xffffffff {
+            return Err(invalid("zip64 archives are not supported"));
+        }
+
+        try!(file.seek(SeekFrom::Start(cd_offset as u64)));
+        let mut cd = vec![0; cd_size as usize];
+        try!(read_exact(&mut file, &mut cd));
+
+        let mut entries = Vec::new();
+        let mut p = 0;
+        for _ in 0..count {
+            if p + 46 > cd.len() || le32(&cd[p..]) != ZIP_CENTRAL_HEADER {
+                return Err(invalid("bad zip central directory"));
+            }
+            let made_by = le16(&cd[p + 4..]) >> 8;
+            let method = le16(&cd[p + 10..]);
+            let mtime = dos_time(le16(&cd[p + 14..]), le16(&cd[p + 12..]));
+            let csize = le32(&cd[p + 20..]) as u64;
+            let size = le32(&cd[p + 24..]) as u64;
+            let name_len = le16(&cd[p + 28..]) as usize;
+            let extra_len = le16(&cd[p + 30..]) as usize;
+            let comment_len = le16(&cd[p + 32..]) as usize;
+            let external = le32(&cd[p + 38..]);
+            let local = le32(&cd[p + 42..]) as u64;
+            if p + 46 + name_len > cd.len() {
+                return Err(invalid("bad zip central directory"));
+            }
+            let name = String::from_utf8_lossy(&cd[p + 46 .. p + 46 + name_len]).into_owned();
+            p += 46 + name_len + extra_len + comment_len;
+
+            let path = clean_path(&name);
+            if path.components().next().is_none() {
+                continue;
+            }
+
+            // Data follows the local header, whose extra field may differ
+            // from the one in the central directory
+            let mut header = [0; 30];
+            try!(file.seek(SeekFrom::Start(local)));
+            try!(read_exact(&mut file, &mut header));
+            if le32(&header) != ZIP_LOCAL_HEADER {
+                return Err(invalid("bad zip local header"));
+            }
+            let start = local + 30 + le16(&header[26..]) as u64 + le16(&header[28..]) as u64;
+
+            let storage = match method {
+                0 => Storage::Stored(start),
+                8 => Storage::Deflated(start, csize),
+                _ => {
+                    warn!("archive: {:?} in {:?} uses compression method {}, skipped",
+                        path, self.path, method);
+                    continue;
+                }
+            };
+            let mode = if made_by == ZIP_HOST_UNIX { external >> 16 } else { 0 };
+            let kind = if name.ends_with("/") || mode & S_IFMT == S_IFDIR {
+                FileType::Directory
+            } else if mode & S_IFMT == S_IFLNK {
+                FileType::Symlink
+            } else {
+                FileType::RegularFile
+            };
+            let perm = match mode & 0o7777 {
+                0 if kind == FileType::Directory => 0o755,
+                0 => 0o644,
+                perm => perm,
+            };
+            entries.push(Entry {
+                path: path, kind: kind, perm: perm as Perm,
+                uid: 0, gid: 0, mtime: mtime, size: size,
+                target: None, storage: storage,
+            });
+        }
+
+        // Zip keeps symlink targets as the entry's contents
+        for entry in entries.iter_mut() {
+            if entry.kind == FileType::Symlink {
+                let mut target = String::new();
+                try!(try!(self.reader(entry, 0)).read_to_string(&mut target));
+                entry.target = Some(target);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// Consume `len` bytes of `r`
+pub fn skip<R: Read>(r: &mut R, len: u64) -> io::Result<()> {
+    let skipped = try!(io::copy(&mut r.take(len), &mut io::sink()));
+    if skipped < len {
+        return Err(invalid("archive is truncated"));
+    }
+    Ok(())
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+// Entry names as stored may start with / or ./ and end with /
+fn clean_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        if let Component::Normal(part) = component {
+            path.push(part);
+        }
+    }
+    path
+}
+
+//
+// tar: ustar and GNU headers, with GNU long names and pax path records
+//
+fn read_tar<R: Read>(r: &mut R) -> io::Result<Vec<Entry>> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut pos = 0;
+    let mut long_name: Option<String> = None;
+    let mut long_link: Option<String> = None;
+
+    loop {
+        let mut header = [0; 512];
+        try!(read_exact(r, &mut header));
+        pos += TAR_BLOCK;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if try!(tar_number(&header[148..156])) != tar_checksum(&header) {
+            return Err(invalid("bad tar header checksum"));
+        }
+
+        let size = try!(tar_number(&header[124..136]));
+        let padded = (size + TAR_BLOCK - 1) / TAR_BLOCK * TAR_BLOCK;
+        let typeflag = header[156];
+
+        // Headers describing the next entry
+        if typeflag == b'L' || typeflag == b'K' || typeflag == b'x' {
+            let mut data = vec![0; size as usize];
+            try!(read_exact(r, &mut data));
+            try!(skip(r, padded - size));
+            pos += padded;
+            match typeflag {
+                b'L' => long_name = Some(c_string(&data)),
+                b'K' => long_link = Some(c_string(&data)),
+                _ => {
+                    for (key, value) in pax_records(&data).into_iter() {
+                        match &key[..] {
+                            "path" => long_name = Some(value),
+                            "linkpath" => long_link = Some(value),
+                            _ => {}
+                        }
+                    }
+                },
+            }
+            continue;
+        }
+
+        let name = long_name.take().unwrap_or_else(|| ustar_name(&header));
+        let link = long_link.take().unwrap_or_else(|| c_string(&header[157..257]));
+        let path = clean_path(&name);
+        let mut entry = Entry {
+            path: path,
+            kind: FileType::RegularFile,
+            perm: (try!(tar_number(&header[100..108])) & 0o7777) as Perm,
+            uid: try!(tar_number(&header[108..116])) as u32,
+            gid: try!(tar_number(&header[116..124])) as u32,
+            mtime: try!(tar_number(&header[136..148])) as i64,
+            size: size,
+            target: None,
+            storage: Storage::Stored(pos),
+        };
+
+        let keep = match typeflag {
+            b'0' | b'7' | 0 => true,
+            b'1' => {
+                // Hard link: share the data of the entry it names
+                let target = clean_path(&link);
+                match entries.iter().find(|e| e.path == target && e.kind == FileType::RegularFile) {
+                    Some(other) => {
+                        entry.size = other.size;
+                        entry.storage = other.storage;
+                        true
+                    },
+                    None => false,
+                }
+            },
+            b'2' => {
+                entry.kind = FileType::Symlink;
+                entry.size = link.len() as u64;
+                entry.target = Some(link);
+                true
+            },
+            b'5' => {
+                entry.kind = FileType::Directory;
+                entry.size = 0;
+                true
+            },
+            _ => false,     /* devices, fifos: nothing to serve */
+        };
+        if keep && entry.path.components().next().is_some() {
+            entries.push(entry);
+        }
+
+        try!(skip(r, padded));
+        pos += padded;
+    }
+    Ok(entries)
+}
+
+// Octal, or base-256 when the high bit of the first byte is set
+fn tar_number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..].iter().fold((field[0] & 0x7f) as u64, |n, &b| n << 8 | b as u64));
+    }
+    let digits: String = field.iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b >= b'0' && b <= b'7')
+        .map(|&b| b as char)
+        .collect();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(&digits, 8).or(Err(invalid("bad number in tar header")))
+}
+
+// Sum of the header bytes, the checksum field counting as spaces
+fn tar_checksum(header: &[u8]) -> u64 {
+    header.iter().enumerate()
+        .map(|(i, &b)| if i >= 148 && i < 156 { b' ' as u64 } else { b as u64 })
+        .fold(0, |sum, b| sum + b)
+}
+
+fn ustar_name(header: &[u8]) -> String {
+    let name = c_string(&header[0..100]);
+    let prefix = c_string(&header[345..500]);
+    if &header[257..262] == &b"ustar"[..] && !prefix.is_empty() {
+        format!("{}/{}", prefix, name)
+    } else {
+        name
+    }
+}
+
+fn c_string(field: &[u8]) -> String {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+// "<len> <key>=<value>\n" records of a pax extended header
+fn pax_records(data: &[u8]) -> Vec<(String, String)> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let len = match String::from_utf8_lossy(&rest[..space]).parse::<usize>() {
+            Ok(len) if len > space + 1 && len <= rest.len() => len,
+            _ => break,
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1 .. len - 1]).into_owned();
+        if let Some(eq) = record.find('=') {
+            records.push((record[..eq].to_owned(), record[eq + 1..].to_owned()));
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+//
+// zip helpers
+//
+fn le16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+fn le32(b: &[u8]) -> u32 {
+    le16(b) as u32 | (le16(&b[2..]) as u32) << 16
+}
+
+// MS-DOS date and time, taken as UTC since zip does not record the zone
+fn dos_time(date: u16, time: u16) -> i64 {
+    let year = 1980 + (date >> 9) as i64;
+    let month = cmp::max(1, (date >> 5) & 0xf) as i64;
+    let day = cmp::max(1, date & 0x1f) as i64;
+    let secs = (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    days_from_civil(year, month, day) * 24 * 60 * 60 + secs
+}
+
+// Days since 1970-01-01 of a Gregorian calendar date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
@@ -0,0 +1,189 @@
+
+use std::io;
+use std::cmp;
+use std::slice;
+use std::rc::Rc;
+use std::io::prelude::*;
+use std::collections::BTreeMap;
+
+use persist;
+
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/* FileAttr.blocks is counted in 512-byte units */
+const BLOCK_UNIT: u64 = 512;
+
+// Sparse file contents. Data lives in fixed-size chunks keyed by
+// chunk index; chunks that were never written (or only with zeros)
+// are holes, read back as zeros and take no memory.
+//
+// Cloning a store is cheap: the clone shares its chunks with the
+// original, and a chunk is copied only when one side writes to it.
+#[derive(Clone, Debug)]
+pub struct ChunkStore {
+    chunks: BTreeMap<u64, Rc<Vec<u8>>>,
+    size: u64,
+}
+
+impl ChunkStore {
+    pub fn new() -> ChunkStore {
+        ChunkStore { chunks: BTreeMap::new(), size: 0 }
+    }
+
+    pub fn len(&self) -> u64 { self.size }
+
+    // Bytes actually held in memory
+    pub fn allocated(&self) -> u64 {
+        self.chunks.len() as u64 * CHUNK_SIZE
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.allocated() / BLOCK_UNIT
+    }
+
+    // Bytes in chunks no other store shares, i.e. freed with this one
+    pub fn unshared(&self) -> u64 {
+        self.chunks.values().filter(|chunk| Rc::strong_count(chunk) == 1).count() as u64 * CHUNK_SIZE
+    }
+
+    pub fn read(&self, offset: u64, size: u64) -> Vec<u8> {
+        if offset >= self.size {
+            return Vec::new();
+        }
+        let end = cmp::min(offset + size, self.size);
+        let mut buf = vec![0; (end - offset) as usize];
+
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / CHUNK_SIZE;
+            let begin = pos % CHUNK_SIZE;
+            let len = cmp::min(CHUNK_SIZE - begin, end - pos);
+            if let Some(chunk) = self.chunks.get(&index) {
+                let dst = (pos - offset) as usize;
+                slice::bytes::copy_memory(
+                    &chunk[begin as usize .. (begin + len) as usize],
+                    &mut buf[dst .. dst + len as usize]);
+            }
+            pos += len;
+        }
+        buf
+    }
+
+    // Bytes write() would newly allocate, so the caller can
+    // account for them before any data is touched. Shared chunks
+    // written to are copied and count too.
+    pub fn allocation_for(&self, offset: u64, data: &[u8]) -> u64 {
+        let mut count = 0;
+        self.each_span(offset, data, |index, _, src| {
+            let allocates = match self.chunks.get(&index) {
+                Some(chunk) => Rc::strong_count(chunk) > 1,
+                None => !is_zero(src),
+            };
+            if allocates {
+                count += CHUNK_SIZE;
+            }
+        });
+        count
+    }
+
+    // Bytes truncate() would newly allocate: a shared chunk
+    // holding the new end is copied to zero what follows it
+    pub fn allocation_for_truncate(&self, size: u64) -> u64 {
+        if size % CHUNK_SIZE == 0 {
+            return 0;
+        }
+        match self.chunks.get(&(size / CHUNK_SIZE)) {
+            Some(chunk) if Rc::strong_count(chunk) > 1 => CHUNK_SIZE,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut spans = Vec::new();
+        self.each_span(offset, data, |index, begin, src| spans.push((index, begin, src)));
+
+        for (index, begin, src) in spans.into_iter() {
+            if !self.chunks.contains_key(&index) {
+                if is_zero(src) {
+                    continue;
+                }
+                self.chunks.insert(index, Rc::new(vec![0; CHUNK_SIZE as usize]));
+            }
+            // unwrap: the chunk was inserted above if missing
+            let chunk = Rc::make_mut(self.chunks.get_mut(&index).unwrap());
+            let begin = begin as usize;
+            slice::bytes::copy_memory(src, &mut chunk[begin .. begin + src.len()]);
+        }
+        self.size = cmp::max(self.size, offset + data.len() as u64);
+    }
+
+    // Returns the number of bytes released, which leaves out
+    // dropped chunks another store still holds
+    pub fn truncate(&mut self, size: u64) -> u64 {
+        let first_dropped = (size + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let dropped: Vec<u64> = self.chunks.keys()
+            .filter(|&&index| index >= first_dropped).cloned().collect();
+        let mut released = 0;
+        for index in dropped.iter() {
+            if let Some(chunk) = self.chunks.remove(index) {
+                if Rc::strong_count(&chunk) == 1 {
+                    released += CHUNK_SIZE;
+                }
+            }
+        }
+
+        // Bytes past the new end must read as zeros if the file grows again
+        let tail = size % CHUNK_SIZE;
+        if tail != 0 {
+            if let Some(chunk) = self.chunks.get_mut(&(size / CHUNK_SIZE)) {
+                for byte in Rc::make_mut(chunk)[tail as usize ..].iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+        self.size = size;
+        released
+    }
+
+    // Only allocated chunks are written, so holes cost nothing on disk either
+    pub fn save<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(persist::write_u64(w, self.size));
+        try!(persist::write_u64(w, self.chunks.len() as u64));
+        for (index, chunk) in self.chunks.iter() {
+            try!(persist::write_u64(w, *index));
+            try!(w.write_all(chunk));
+        }
+        Ok(())
+    }
+
+    pub fn load<R: Read>(r: &mut R) -> io::Result<ChunkStore> {
+        let size = try!(persist::read_u64(r));
+        let count = try!(persist::read_u64(r));
+        let mut store = ChunkStore { chunks: BTreeMap::new(), size: size };
+        for _ in 0..count {
+            let index = try!(persist::read_u64(r));
+            let mut chunk = vec![0; CHUNK_SIZE as usize];
+            try!(persist::read_exact(r, &mut chunk));
+            store.chunks.insert(index, Rc::new(chunk));
+        }
+        Ok(store)
+    }
+
+    // Split a write at `offset` into (chunk index, offset in chunk, data)
+    fn each_span<'a, F>(&self, offset: u64, data: &'a [u8], mut f: F)
+        where F: FnMut(u64, u64, &'a [u8])
+    {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let begin = pos % CHUNK_SIZE;
+            let len = cmp::min((CHUNK_SIZE - begin) as usize, data.len() - done);
+            f(pos / CHUNK_SIZE, begin, &data[done .. done + len]);
+            done += len;
+        }
+    }
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|&b| b == 0)
+}
@@ -7,9 +7,104 @@ use std::path;
 pub type Path = path::Path;
 pub type PathBuf = path::PathBuf;
 
+use std::io;
+use std::fmt;
+use std::error;
 use std::result;
 use self::libc::c_int;  /* type of errno */
-pub type Result<T> = result::Result<T, c_int>;
+use self::libc::consts::os::posix88::*;
+pub type Result<T> = result::Result<T, Error>;
+
+// The errno replied to the kernel, with what is known of where it came
+// from. Context is set where it is first known and kept from then on.
+#[derive(Debug)]
+pub struct Error {
+    errno: c_int,
+    path: Option<PathBuf>,
+    ops: Option<String>,
+    cause: Option<io::Error>,
+}
+
+impl Error {
+    pub fn new(errno: c_int) -> Error {
+        Error { errno: errno, path: None, ops: None, cause: None }
+    }
+
+    pub fn errno(&self) -> c_int { self.errno }
+    pub fn path(&self) -> Option<&Path> { self.path.as_ref().map(|path| path.as_path()) }
+    pub fn ops(&self) -> Option<&str> { self.ops.as_ref().map(|name| &name[..]) }
+    pub fn io_error(&self) -> Option<&io::Error> { self.cause.as_ref() }
+
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Error {
+        if self.path.is_none() {
+            self.path = Some(path.as_ref().to_path_buf());
+        }
+        self
+    }
+
+    pub fn with_ops(mut self, name: &str) -> Error {
+        if self.ops.is_none() {
+            self.ops = Some(name.to_owned());
+        }
+        self
+    }
+}
+
+impl From<c_int> for Error {
+    fn from(errno: c_int) -> Error {
+        Error::new(errno)
+    }
+}
+
+// The OS error if there is one, else the closest errno to its kind
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        let errno = err.raw_os_error().unwrap_or(match err.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            io::ErrorKind::PermissionDenied => EACCES,
+            io::ErrorKind::ConnectionRefused => ECONNREFUSED,
+            io::ErrorKind::ConnectionReset => ECONNRESET,
+            io::ErrorKind::ConnectionAborted => ECONNABORTED,
+            io::ErrorKind::NotConnected => ENOTCONN,
+            io::ErrorKind::AddrInUse => EADDRINUSE,
+            io::ErrorKind::AddrNotAvailable => EADDRNOTAVAIL,
+            io::ErrorKind::BrokenPipe => EPIPE,
+            io::ErrorKind::AlreadyExists => EEXIST,
+            io::ErrorKind::WouldBlock => EAGAIN,
+            io::ErrorKind::InvalidInput => EINVAL,
+            io::ErrorKind::TimedOut => ETIMEDOUT,
+            io::ErrorKind::Interrupted => EINTR,
+            _ => EIO,
+        });
+        Error { cause: Some(err), ..Error::new(errno) }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref name) = self.ops {
+            try!(write!(f, "{}: ", name));
+        }
+        if let Some(ref path) = self.path {
+            try!(write!(f, "{}: ", path.display()));
+        }
+        match self.cause {
+            Some(ref err) if err.raw_os_error().is_some() => write!(f, "{}", err),
+            Some(ref err) => write!(f, "{} (errno {})", err, self.errno),
+            None => write!(f, "{}", io::Error::from_raw_os_error(self.errno)),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        "filesystem error"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        self.cause.as_ref().map(|err| err as &error::Error)
+    }
+}
 
 use std::rc::Rc;
 use std::cell::RefCell;
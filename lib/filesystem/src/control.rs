@@ -0,0 +1,264 @@
+
+extern crate libc;
+extern crate fuse;
+
+use std::env;
+use std::rc::Rc;
+use std::cell::RefCell;
+use self::fuse::{FileType, FileAttr};
+use self::libc::consts::os::posix88::*; /* POSIX errno, open flags */
+
+use fs::*;
+use common::*;
+use ops::{Operations, PassthroughOps, OverlayOps, ArchiveOps};
+use snapshot::SnapshotOps;
+use router::{Route, parse_kind};
+use ctl::{CtlCommands, CtlOps};
+use core::{BasicFileSystem, Priority, get_path};
+
+/* Runtime control and introspection files live here */
+pub const CONTROL_DIR: &'static str = "/.wlfs";
+
+// Constructor of a loadable ops provider, given the
+// arguments that follow its name and priority in `load`
+pub type Provider = fn(&[&str]) -> Result<RcRefBox<Operations>>;
+
+// Providers of the filesystem crate
+pub static PROVIDERS: &'static [(&'static str, Provider)] = &[
+    ("filesystem.snapshot", snapshot_provider as Provider),
+    ("filesystem.passthrough", passthrough_provider as Provider),
+    ("filesystem.overlay", overlay_provider as Provider),
+    ("filesystem.archive", archive_provider as Provider),
+];
+
+fn snapshot_provider(_args: &[&str]) -> Result<RcRefBox<Operations>> {
+    Ok(SnapshotOps::new())
+}
+
+// Relative host paths are taken from where wlfs was started, which
+// a daemon leaves for / once its providers are loaded
+fn host_path(arg: &str) -> Result<String> {
+    let cwd = try!(env::current_dir().map_err(Error::from));
+    Ok(cwd.join(arg).to_str().unwrap().to_owned())
+}
+
+// <prefix> <host directory>
+fn passthrough_provider(args: &[&str]) -> Result<RcRefBox<Operations>> {
+    if args.len() != 2 {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(PassthroughOps::new(args[0], &try!(host_path(args[1]))))
+}
+
+// <prefix> <lower host directory or archive file>
+fn overlay_provider(args: &[&str]) -> Result<RcRefBox<Operations>> {
+    if args.len() != 2 {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(OverlayOps::new(args[0], &try!(host_path(args[1]))))
+}
+
+// <prefix> <archive file>
+fn archive_provider(args: &[&str]) -> Result<RcRefBox<Operations>> {
+    if args.len() != 2 {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(ArchiveOps::new(args[0], &try!(host_path(args[1]))))
+}
+
+//
+// Control Operations
+//
+// Provider for CONTROL_DIR:
+//
+//  ctl         write "load <provider> <priority> [args...]",
+//              "unload <ops name>" or "explain <path> [kind]", one
+//              command per line; reading it gives the last explanation
+//              or why the last command failed (see the ctl module)
+//  ops         registered ops in priority order, "<priority> <name>"
+//  routes      router rules in the order they are tried, then
+//              "overlap <rule> / <rule>" for ambiguous pairs
+//  providers   provider names load accepts
+//  fds         open handles, "<handle> <inode> <path> <handler>"
+//  stats       counters, "<name> <value>"
+//
+// Nothing else can be created there.
+//
+pub struct ControlOps;
+
+impl ControlOps {
+    pub fn new() -> RcRefBox<Operations> { RcRefBox!(ControlOps) }
+}
+
+impl Operations for ControlOps {
+    fn name(&self) -> &str {
+        "filesystem.ControlOps"
+    }
+
+    fn new_ops(&self) -> RcRefBox<Operations> {
+        Self::new()
+    }
+
+    fn install(&mut self, fs: &mut BasicFileSystem) -> bool {
+        let path = Path::new(CONTROL_DIR);
+        if fs.lookup_path(path).is_some() {
+            error!("install: {:?} already exists", path);
+            return false;
+        }
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let attr = FileAttr { ino: fs.alloc_inode(), perm: 0o555, ..fileattr_new() };
+        let dir = RcRef!(Dir::new(name, attr, None, Self::new()));
+        let root = fs.root();
+        if fs.link_node(&root, Node::Dir(dir.clone())).is_err() {
+            return false;
+        }
+
+        let (uid, gid) = fs.owner();
+        let attr = FileAttr { ino: fs.alloc_inode(), perm: 0o600, uid: uid, gid: gid, ..fileattr_new() };
+        let ctl = RcRef!(File::new("ctl", attr, None, CtlOps::new(commands())));
+        if fs.link_node(&dir, Node::File(ctl)).is_err() {
+            return false;
+        }
+
+        let files = vec![
+            ("ops", list_ops as Generator),
+            ("routes", list_routes as Generator),
+            ("providers", list_providers as Generator),
+            ("fds", list_fds as Generator),
+            ("stats", list_stats as Generator),
+        ];
+        for (name, generate) in files.into_iter() {
+            let created = fs.synthetic_file(path.join(name))
+                .on_read(move |fs| Ok(generate(fs).into_bytes()))
+                .create();
+            if created.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn uninstall(&mut self, fs: &mut BasicFileSystem) -> bool {
+        let path = Path::new(CONTROL_DIR);
+        if let Some(node) = fs.lookup_path(path) {
+            let root = fs.root();
+            fs.unlink_node(&root, &node);
+        }
+        true
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::subtree(CONTROL_DIR)]
+    }
+
+    fn mknod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _perm: Perm) -> Result<()> {
+        Err(Error::new(EPERM))
+    }
+
+    fn rmnod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
+        Err(Error::new(EPERM))
+    }
+
+    fn rename(&mut self, _fs: &mut BasicFileSystem, _ino: Inode,
+              _newparent: Inode, _newname: &str) -> Result<()> {
+        Err(Error::new(EPERM))
+    }
+
+    // Rebuilt on install
+    fn persistent(&self) -> bool { false }
+}
+
+// Contents of a status file
+type Generator = fn(&BasicFileSystem) -> String;
+
+fn list_ops(fs: &BasicFileSystem) -> String {
+    let mut text = String::new();
+    for &(priority, ref name) in fs.registered_ops().iter() {
+        text.push_str(&format!("{} {}\n", priority, name));
+    }
+    text
+}
+
+fn list_routes(fs: &BasicFileSystem) -> String {
+    let mut text = String::new();
+    for rule in fs.router().rules().iter() {
+        text.push_str(&format!("{}\n", rule));
+    }
+    for &(ref a, ref b) in fs.router().overlaps().iter() {
+        text.push_str(&format!("overlap {} / {}\n", a, b));
+    }
+    text
+}
+
+fn list_providers(fs: &BasicFileSystem) -> String {
+    let mut text = String::new();
+    for name in fs.providers().iter() {
+        text.push_str(&format!("{}\n", name));
+    }
+    text
+}
+
+// Handles whose node is gone show as "(deleted)"
+fn list_fds(fs: &BasicFileSystem) -> String {
+    let mut text = String::new();
+    for &(fh, ino, ref handler) in fs.open_handles().iter() {
+        let path = match fs.find_node(ino) {
+            Some(node) => format!("{}", get_path(fs, node).display()),
+            None => "(deleted)".to_owned(),
+        };
+        text.push_str(&format!("{} {} {} {}\n", fh, ino, path, handler));
+    }
+    text
+}
+
+fn list_stats(fs: &BasicFileSystem) -> String {
+    let (bytes, max_bytes) = {
+        let usage = fs.usage();
+        let usage = usage.borrow();
+        (usage.bytes(), usage.max_bytes())
+    };
+    let limit = |max: Option<u64>| max.map_or("unlimited".to_owned(), |max| max.to_string());
+    let stats = vec![
+        ("inodes", fs.inode_count().to_string()),
+        ("max_inodes", limit(fs.max_inodes())),
+        ("next_inode", fs.next_inode().to_string()),
+        ("open_handles", fs.open_handles().len().to_string()),
+        ("next_handle", fs.next_handle().to_string()),
+        ("ops", fs.registered_ops().len().to_string()),
+        ("bytes", bytes.to_string()),
+        ("max_bytes", limit(max_bytes)),
+        ("snapshots", fs.snapshots().len().to_string()),
+    ];
+    let mut text = String::new();
+    for (name, value) in stats.into_iter() {
+        text.push_str(&format!("{} {}\n", name, value));
+    }
+    text
+}
+
+fn commands() -> CtlCommands {
+    CtlCommands::new("ControlOps.CtlHandler")
+        .command("load <provider> <priority> [args...]", |fs, args| {
+            let priority = try!(args[1].parse::<Priority>().or(Err(Error::new(EINVAL))));
+            try!(fs.load(args[0], priority, &args[2..]));
+            Ok(None)
+        })
+        .command("unload <ops>", |fs, args| {
+            try!(fs.unload(args[0]));
+            Ok(None)
+        })
+        .command("explain <path> [kind]", |fs, args| {
+            let path = Path::new(args[0]);
+            // Without a kind, that of the node at `path`, if any
+            let kind = match args.get(1) {
+                Some(name) => try!(parse_kind(name).ok_or(Error::new(EINVAL))),
+                None => fs.lookup_path(path)
+                    .map_or(FileType::RegularFile, |node| node.attr().kind),
+            };
+            let mut text = String::new();
+            for line in fs.explain(path, kind).iter() {
+                text.push_str(&format!("{}\n", line));
+            }
+            Ok(Some(text))
+        })
+}
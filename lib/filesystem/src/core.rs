@@ -3,31 +3,129 @@ extern crate libc;
 extern crate time;
 extern crate fuse;
 
+use std::cmp;
+use std::mem;
+use std::result;
+use std::io::prelude::*;
+use std::fs as hostfs;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
+use std::path::Component;
 
 use ops;
+use lock;
+use persist;
+use journal;
+use journal::Entry;
+use snapshot;
+use router::{Router, Route};
+use synthetic::SyntheticFile;
+use control::Provider;
 use common::*;
 use fs::*;
 
 use self::time::Timespec;
-use self::libc::consts::os::posix88::*; /* POSIX errno */
+use self::libc::c_int;
+use self::libc::consts::os::posix88::*; /* POSIX errno, open flags */
 use self::fuse::consts::*;
 use self::fuse::{FileType, FileAttr};
 use self::fuse::{Request, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr};
-use self::fuse::{ReplyOpen, ReplyWrite, ReplyStatfs, ReplyDirectory};
+use self::fuse::{ReplyOpen, ReplyWrite, ReplyStatfs, ReplyDirectory, ReplyLock};
 
 pub type Handle   = u64;
 pub type Priority = u32;
 
+const BLOCK_SIZE: u32 = 4096;
+const NAME_MAX: u32 = 255;
+/* Where statfs finds the memory left when no byte limit is configured */
+const MEMINFO: &'static str = "/proc/meminfo";
+/* Journal records between two compactions into the snapshot */
+const COMPACT_EVERY: u64 = 1024;
+
+// Bytes held by FileOps data across the whole tree, shared with every
+// FileHandler so writes can be refused once the configured limit is hit.
+// A chunk shared with snapshots is charged once, and stays charged while
+// a snapshot still holds it; the copy a write makes of it is charged too.
+#[derive(Debug)]
+pub struct Usage {
+    bytes: u64,
+    max_bytes: Option<u64>,
+}
+
+impl Usage {
+    pub fn bytes(&self) -> u64 { self.bytes }
+    pub fn max_bytes(&self) -> Option<u64> { self.max_bytes }
+
+    pub fn is_full(&self) -> bool {
+        self.max_bytes.map_or(false, |max| self.bytes >= max)
+    }
+
+    pub fn reserve(&mut self, size: u64) -> Result<()> {
+        match self.max_bytes {
+            Some(max) if self.bytes + size > max => Err(Error::new(ENOSPC)),
+            _ => { self.bytes += size; Ok(()) }
+        }
+    }
+
+    pub fn release(&mut self, size: u64) {
+        self.bytes = if size < self.bytes { self.bytes - size } else { 0 };
+    }
+}
+
+// When reads update atime, as with the noatime/relatime mount options
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AtimePolicy {
+    Strict,
+    Relatime,
+    Noatime,
+}
+
+/* Timestamps to update with BasicFileSystem::touch() */
+pub const TOUCH_ATIME: u32 = 0x1;
+pub const TOUCH_MTIME: u32 = 0x2;
+pub const TOUCH_CTIME: u32 = 0x4;
+
+// An F_SETLKW request waiting for conflicting locks to go away
+struct LockWaiter {
+    ino: Inode,
+    lock: lock::Lock,
+    reply: ReplyEmpty,
+}
+
+// An open handle: the node it was opened on and its handler
+struct OpenFile {
+    ino: Inode,
+    handler: RcRefBox<ops::OpenHandler>,
+}
+
 pub struct BasicFileSystem {
     root: RcRef<Dir>,   // Filesystem tree
     inodes: HashMap<Inode, Node>,
     next_inode: Inode,
+    expired: HashMap<Inode, (Inode, String)>,   // parent and name of expired nodes
     ops: PriorityQueue<Priority, RcRefBox<ops::Operations>>,
-    openfds: HashMap<Handle, RcRefBox<ops::OpenHandler>>,
+    router: Router,
+    openfds: HashMap<Handle, OpenFile>,
     next_handle: Handle,
+    locks: lock::LockManager,
+    lock_waiters: Vec<LockWaiter>,
+    opener_pid: u32,    // of the open being handled
+    usage: RcRef<Usage>,
+    max_inodes: Option<u64>,
+    owner: (u32, u32),  // uid, gid of created nodes
+    read_only: bool,
+    atime_policy: AtimePolicy,
+    snapshot_path: Option<PathBuf>,
+    journal_path: Option<PathBuf>,
+    journal: Option<journal::Journal>,
+    journal_seq: u64,   // of the last change applied to the tree
+    compact_every: u64,
+    snapshots: BTreeMap<String, snapshot::Snapshot>,
+    providers: Vec<(&'static str, Provider)>,
+    mounted: bool,      // init() went through
+    teardown_report: Option<ArcRef<Result<()>>>,
+    mount_report: Option<Box<FnMut(&Result<()>)>>,
 }
 
 // NOTE::
@@ -61,9 +159,29 @@ impl BasicFileSystem {
             root: root.clone(),
             inodes: HashMap::new(),
             next_inode: 2,
+            expired: HashMap::new(),
             ops: PriorityQueue::new(),
+            router: Router::new(),
             openfds: HashMap::new(),
             next_handle: 1,
+            locks: lock::LockManager::new(),
+            lock_waiters: Vec::new(),
+            opener_pid: 0,
+            usage: RcRef!(Usage { bytes: 0, max_bytes: None }),
+            max_inodes: None,
+            owner: (0, 0),
+            read_only: false,
+            atime_policy: AtimePolicy::Relatime,
+            snapshot_path: None,
+            journal_path: None,
+            journal: None,
+            journal_seq: 0,
+            compact_every: COMPACT_EVERY,
+            snapshots: BTreeMap::new(),
+            providers: Vec::new(),
+            mounted: false,
+            teardown_report: None,
+            mount_report: None,
         };
 
         fs.register_node(Node::Dir(root));
@@ -75,24 +193,112 @@ impl BasicFileSystem {
     fn get_ops(&self, path: &Path, kind: FileType) -> RcRefBox<ops::Operations> {
         // unwrap: At least default operations
         // (FileOps, DirOps) must be available after new()
-        self.ops.find(|&&(_, ref t)| t.borrow_mut().is_target(path, kind)).unwrap().1.clone()
+        self.router.route(path, kind).unwrap()
+    }
+
+    // How get_ops() picks the ops for `path`
+    pub fn explain(&self, path: &Path, kind: FileType) -> Vec<String> {
+        self.router.explain(path, kind)
     }
 
-    pub fn register_ops(&mut self, p: Priority, ops: RcRefBox<ops::Operations>) {
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    // Registered operations by name, e.g. to rebind restored nodes
+    pub fn find_ops(&self, name: &str) -> Option<RcRefBox<ops::Operations>> {
+        self.ops.find(|&&(_, ref t)| t.borrow().name() == name).map(|&(_, ref t)| t.clone())
+    }
+
+    // false if ops of the same name are registered already, or if the
+    // ops refused to install
+    pub fn register_ops(&mut self, p: Priority, ops: RcRefBox<ops::Operations>) -> bool {
+        let name = ops.borrow().name().to_owned();
+        if self.find_ops(&name).is_some() {
+            error!("register_ops: {} is registered already", name);
+            return false;
+        }
         if ops.borrow_mut().install(self) {
             info!("register_ops: {} installed", ops.borrow().name());
-            self.ops.add(p, ops)
+            let mut routes = ops.borrow().routes();
+            if routes.is_empty() {
+                routes.push(Route::target());
+            }
+            self.router.add(p, routes, ops.clone());
+            self.ops.add(p, ops);
+            return true;
         }
+        false
     }
 
-    pub fn unregister_ops(&mut self, name: &str) {
+    // false if the ops failed to uninstall
+    pub fn unregister_ops(&mut self, name: &str) -> bool {
         let result = self.ops.remove(|&(_, ref t)| t.borrow().name() == name);
         if result.is_some() {
             // unwrap: result.is_some() == true
             let ops = result.unwrap().1;
-            ops.borrow_mut().uninstall(self);
+            self.router.remove(name);
+            if !ops.borrow_mut().uninstall(self) {
+                error!("unregister_ops: {} failed to uninstall", ops.borrow().name());
+                return false;
+            }
             info!("unregister_ops: {} uninstalled", ops.borrow().name());
         }
+        true
+    }
+
+    // Registered ops in priority order
+    pub fn registered_ops(&self) -> Vec<(Priority, String)> {
+        self.ops.iter().map(|&(p, ref t)| (p, t.borrow().name().to_owned())).collect()
+    }
+
+    // (handle, inode, handler name) of every open handle
+    pub fn open_handles(&self) -> Vec<(Handle, Inode, String)> {
+        let mut handles: Vec<_> = self.openfds.iter()
+            .map(|(&fh, file)| (fh, file.ino, file.handler.borrow().name().to_owned()))
+            .collect();
+        handles.sort_by(|a, b| a.0.cmp(&b.0));
+        handles
+    }
+
+    pub fn inode_count(&self) -> u64 { self.inodes.len() as u64 }
+    pub fn next_inode(&self) -> Inode { self.next_inode }
+    pub fn next_handle(&self) -> Handle { self.next_handle }
+    pub fn max_inodes(&self) -> Option<u64> { self.max_inodes }
+
+    // Make providers available to load()
+    pub fn add_providers(&mut self, providers: &[(&'static str, Provider)]) {
+        self.providers.extend(providers.iter().cloned());
+    }
+
+    pub fn providers(&self) -> Vec<&'static str> {
+        self.providers.iter().map(|&(name, _)| name).collect()
+    }
+
+    // Build ops with provider `name` and register them. Ops are known by
+    // name, so only one instance of each can be loaded at a time.
+    pub fn load(&mut self, name: &str, p: Priority, args: &[&str]) -> Result<()> {
+        let provider = try!(self.providers.iter()
+            .find(|&&(n, _)| n == name).map(|&(_, provider)| provider).ok_or(Error::new(ENOENT)));
+        let ops = try!(provider(args));
+        let ops_name = ops.borrow().name().to_owned();
+        if self.find_ops(&ops_name).is_some() {
+            return Err(Error::new(EEXIST));
+        }
+        if !self.register_ops(p, ops) {
+            return Err(Error::new(EIO));
+        }
+        Ok(())
+    }
+
+    // get_ops() relies on the default DirOps and FileOps being there
+    pub fn unload(&mut self, name: &str) -> Result<()> {
+        if name == "filesystem.DirOps" || name == "filesystem.FileOps" {
+            return Err(Error::new(EPERM));
+        }
+        try!(self.find_ops(name).ok_or(Error::new(ENOENT)));
+        self.unregister_ops(name);
+        Ok(())
     }
 
     fn register_node(&mut self, node: Node) {
@@ -107,34 +313,650 @@ impl BasicFileSystem {
         self.inodes.get(&ino)
     }
 
+    pub fn root(&self) -> RcRef<Dir> {
+        self.root.clone()
+    }
+
+    pub fn lookup_path(&self, path: &Path) -> Option<Node> {
+        let mut node = Node::Dir(self.root.clone());
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name.to_str().unwrap(),
+                _ => continue,
+            };
+            let next = match node {
+                Node::Dir(ref dir) => dir.borrow().find_node(name).cloned(),
+                Node::File(_) => None,
+            };
+            node = match next {
+                Some(next) => next,
+                None => return None,
+            };
+        }
+        Some(node)
+    }
+
+    // Parent directory and final name of `path`
+    fn lookup_parent(&self, path: &Path) -> Result<(RcRef<Dir>, PathBuf)> {
+        let parent = try!(path.parent().and_then(|p| self.lookup_path(p)).ok_or(Error::new(ENOENT)));
+        let name = try!(path.file_name().ok_or(Error::new(EINVAL)));
+        if !parent.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        Ok((parent.to_dir().clone(), PathBuf::from(name)))
+    }
+
+    pub fn alloc_inode(&mut self) -> Inode {
+        self.next_inode += 1;
+        self.next_inode - 1
+    }
+
+    // Attach an already built node without running Operations::mknod,
+    // for trees rebuilt from saved state
+    pub fn link_node(&mut self, parent_dir: &RcRef<Dir>, mut node: Node) -> Result<()> {
+        try!(self.check_capacity());
+        let parent = parent_dir.borrow().attr().ino;
+        if let Some(ino) = self.take_expired(parent, &node.name()) {
+            let attr = FileAttr { ino: ino, ..node.attr() };
+            node.set_attr(attr);
+        }
+        try!(parent_dir.borrow_mut().mknod(node.clone()));
+        self.register_node(node);
+        Ok(())
+    }
+
+    // A file generated by closures, see the synthetic module
+    pub fn synthetic_file<P: AsRef<Path>>(&mut self, path: P) -> SyntheticFile {
+        SyntheticFile::new(self, path.as_ref())
+    }
+
+    // Detach a node and its subtree without running Operations::rmnod
+    pub fn unlink_node(&mut self, parent_dir: &RcRef<Dir>, node: &Node) {
+        let _ = parent_dir.borrow_mut().rmnod(&node.name(), node.attr().kind);
+        self.unregister_tree(node);
+    }
+
+    fn unregister_tree(&mut self, node: &Node) {
+        if node.is_dir() {
+            let children: Vec<Node> = node.to_dir().borrow().nodes().values().cloned().collect();
+            for child in children.iter() {
+                self.unregister_tree(child);
+            }
+        }
+        self.unregister_node(node.attr().ino);
+        self.forget_locks(node.attr().ino);
+    }
+
+    // Find a directory by path, creating missing components like mkdir -p
+    pub fn mkdir_all(&mut self, path: &Path, mode: u32) -> Result<RcRef<Dir>> {
+        let mut dir = self.root.clone();
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name.to_str().unwrap(),
+                _ => continue,
+            };
+            let next = dir.borrow().find_node(name).cloned();
+            dir = match next {
+                Some(Node::Dir(next)) => next,
+                Some(Node::File(_)) => return Err(Error::new(ENOTDIR)),
+                None => try!(self.mkdir(&dir, name.as_ref(), mode)),
+            };
+        }
+        Ok(dir)
+    }
+
+    // Entry `name` of a directory, asking the directory's ops when it is
+    // not in the tree
+    pub fn lookup(&mut self, parent_dir: &RcRef<Dir>, name: &str) -> Result<Node> {
+        self.expire(parent_dir);
+        let found = parent_dir.borrow().find_node(name).cloned();
+        match found {
+            Some(node) => Ok(node),
+            None => {
+                let (_ops, ino) = {
+                    let dir = parent_dir.borrow();
+                    (dir.ops(), dir.attr().ino)
+                };
+                let mut ops = _ops.borrow_mut();
+                let found = try!(ops.lookup(self, ino, name).map_err(|err| err.with_ops(ops.name())));
+                let node = match found {
+                    Some(node) => node,
+                    None => {
+                        // Gone for good, as is the inode it had
+                        self.take_expired(ino, name);
+                        return Err(Error::new(ENOENT));
+                    },
+                };
+                if let Some(ttl) = ops.populate_ttl() {
+                    parent_dir.borrow_mut().cache(name, time::get_time() + ttl);
+                }
+                Ok(node)
+            }
+        }
+    }
+
+    // Drop the nodes the ops of `dir` added once their TTL is up, with
+    // their subtree, unless something in it is open. The kernel may
+    // still hold their inode numbers; they are remembered so that the
+    // nodes get them back when added again, see revive().
+    fn expire(&mut self, dir: &RcRef<Dir>) {
+        let names = dir.borrow().expired(time::get_time());
+        for name in names.iter() {
+            let node = dir.borrow().find_node(name).cloned();
+            match node {
+                Some(ref node) if self.is_open(node) => continue,
+                Some(ref node) => {
+                    debug!("expire: {:?}", get_path(self, node));
+                    self.remember_expired(node);
+                    self.unlink_node(dir, node);
+                    dir.borrow_mut().set_listed(None);
+                },
+                None => {},
+            }
+            dir.borrow_mut().uncache(name);
+        }
+    }
+
+    fn remember_expired(&mut self, node: &Node) {
+        if node.is_dir() {
+            let children: Vec<Node> = node.to_dir().borrow().nodes().values().cloned().collect();
+            for child in children.iter() {
+                self.remember_expired(child);
+            }
+        }
+        if let Some(parent) = node.parent() {
+            self.expired.insert(node.attr().ino, (parent, node.name()));
+        }
+    }
+
+    // Inode number an expired node `name` of `parent` had
+    fn take_expired(&mut self, parent: Inode, name: &str) -> Option<Inode> {
+        let ino = self.expired.iter()
+            .find(|&(_, &(p, ref n))| p == parent && n == name)
+            .map(|(&ino, _)| ino);
+        if let Some(ino) = ino {
+            self.expired.remove(&ino);
+        }
+        ino
+    }
+
+    // Look an expired node up again for a request on its old inode
+    fn revive(&mut self, ino: Inode) -> Result<Node> {
+        let (parent, name) = try!(self.expired.get(&ino).cloned().ok_or(Error::new(ENOENT)));
+        let parent_dir = try!(self.node(parent));
+        if !parent_dir.is_dir() {
+            return Err(Error::new(ENOENT));
+        }
+        let node = try!(self.lookup(parent_dir.to_dir(), &name));
+        if node.attr().ino != ino {
+            return Err(Error::new(ENOENT));
+        }
+        Ok(node)
+    }
+
+    // Whether changes to `node` go to the journal: only if it and every
+    // directory above it are persistent(), as for snapshots
+    fn is_journaled(&self, node: &Node) -> bool {
+        if !node.ops().borrow().persistent() {
+            return false;
+        }
+        match node.parent().and_then(|parent| self.find_node(parent)) {
+            Some(parent) => self.is_journaled(parent),
+            None => true,
+        }
+    }
+
+    fn is_journaled_entry(&self, parent_dir: &Node, name: &Path) -> bool {
+        let node = parent_dir.to_dir().borrow().find_node(name.to_str().unwrap()).cloned();
+        node.map_or(false, |node| self.is_journaled(&node))
+    }
+
+    // Whether a handle is open on `node` or below it
+    fn is_open(&self, node: &Node) -> bool {
+        let ino = node.attr().ino;
+        if self.openfds.values().any(|file| file.ino == ino) {
+            return true;
+        }
+        node.is_dir() && node.to_dir().borrow().nodes().values().any(|child| self.is_open(child))
+    }
+
+    pub fn getattr(&mut self, node: &Node) -> Result<FileAttr> {
+        let _ops = node.ops();
+        let mut ops = _ops.borrow_mut();
+        ops.getattr(self, node.clone()).map_err(|err| err.with_ops(ops.name()))
+    }
+
+    // Snapshot file written on unmount and loaded on mount
+    pub fn set_snapshot_path(&mut self, path: Option<PathBuf>) {
+        self.snapshot_path = path;
+    }
+
+    // Journal of changes since the last snapshot, replayed on mount
+    pub fn set_journal_path(&mut self, path: Option<PathBuf>) {
+        self.journal_path = path;
+    }
+
+    pub fn set_compact_every(&mut self, records: u64) {
+        self.compact_every = records;
+    }
+
+    // Seq of the last journal record the tree includes, saved with it
+    pub fn journal_seq(&self) -> u64 {
+        self.journal_seq
+    }
+
+    // Called once a change has been applied and before it is replied to,
+    // so a change is durable by the time the kernel hears it succeeded.
+    // Compaction happens here too, with this change in the tree.
+    fn log(&mut self, entry: Entry) -> Result<()> {
+        let seq = self.journal_seq + 1;
+        let due = match self.journal {
+            Some(ref mut journal) => {
+                try!(journal.append(seq, &entry));
+                journal.records() >= self.compact_every
+            },
+            None => return Ok(()),
+        };
+        self.journal_seq = seq;
+        if due {
+            // The change is in the journal already, a failed compaction
+            // only leaves more to replay
+            if let Err(err) = self.compact() {
+                warn!("log: cannot compact into {:?} ({})", self.snapshot_path, err);
+            }
+        }
+        Ok(())
+    }
+
+    // Fold the journal into the snapshot. Without a snapshot
+    // file the journal keeps the whole history instead.
+    pub fn compact(&mut self) -> Result<()> {
+        if let Some(path) = self.snapshot_path.clone() {
+            try!(persist::save(self, &path));
+            if let Some(ref mut journal) = self.journal {
+                try!(journal.clear());
+            }
+        }
+        Ok(())
+    }
+
+    // Errors end here: log them with the path of `ino` unless they carry
+    // a path already, and give the errno for the reply
+    fn fail(&self, op: &str, ino: Inode, err: Error) -> c_int {
+        let err = match self.find_node(ino) {
+            Some(node) => err.with_path(get_path(self, node)),
+            None => err,
+        };
+        if err.errno() == ENOENT {
+            debug!("{}: {}", op, err);
+        } else {
+            warn!("{}: {}", op, err);
+        }
+        err.errno()
+    }
+
+    // Save the tree if it was mounted, then unregister every ops so their
+    // uninstall hooks run. An error if either step failed.
+    pub fn teardown(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        if self.mounted {
+            if let Err(err) = self.compact() {
+                error!("teardown: cannot save snapshot to {:?} ({})",
+                       self.snapshot_path, err);
+                result = Err(err);
+            }
+            self.mounted = false;
+        }
+        let names: Vec<_> = self.ops.iter()
+            .map(|&(_, ref t)| t.borrow().name().to_owned()).collect();
+        for ref ops_name in names {
+            if !self.unregister_ops(ops_name) {
+                result = Err(Error::new(EIO));
+            }
+        }
+        result
+    }
+
+    // Where teardown() reports when the filesystem is dropped, which
+    // happens inside fuse::mount()
+    pub fn set_teardown_report(&mut self, report: ArcRef<Result<()>>) {
+        self.teardown_report = Some(report);
+    }
+
+    // Called once with the result of the kernel's init, i.e. when the
+    // mount is up or has failed, e.g. for a daemon to tell its parent
+    pub fn set_mount_report<F>(&mut self, report: F) where F: FnMut(&Result<()>) + 'static {
+        self.mount_report = Some(Box::new(report));
+    }
+
+    // Records the snapshot already includes are skipped
+    fn replay(&mut self, entries: Vec<(u64, Entry)>) {
+        let mut count = 0;
+        for (seq, entry) in entries.into_iter() {
+            if seq <= self.journal_seq {
+                continue;
+            }
+            if let Err(err) = self.apply(&entry) {
+                warn!("replay: {:?} failed ({})", entry, err);
+            }
+            self.journal_seq = seq;
+            count += 1;
+        }
+        info!("replay: {} journal records applied", count);
+    }
+
+    fn apply(&mut self, entry: &Entry) -> Result<()> {
+        match *entry {
+            Entry::Mkdir(ref path, mode) => {
+                let (parent_dir, name) = try!(self.lookup_parent(path));
+                self.mkdir(&parent_dir, &name, mode).and(Ok(()))
+            },
+            Entry::Mkfile(ref path, mode) => {
+                let (parent_dir, name) = try!(self.lookup_parent(path));
+                self.mkfile(&parent_dir, &name, mode).and(Ok(()))
+            },
+            Entry::Rmnod(ref path, is_dir) => {
+                let (parent_dir, name) = try!(self.lookup_parent(path));
+                let kind = if is_dir { FileType::Directory } else { FileType::RegularFile };
+                self.rmnod(&parent_dir, &name, kind)
+            },
+            Entry::Rename(ref from, ref to) => {
+                let (parent_dir, name) = try!(self.lookup_parent(from));
+                let (new_parent_dir, newname) = try!(self.lookup_parent(to));
+                self.rename(&parent_dir, name.to_str().unwrap(),
+                            &new_parent_dir, newname.to_str().unwrap())
+            },
+            Entry::Setattr(ref path, attr, truncate) => {
+                let mut node = try!(self.lookup_path(path).ok_or(Error::new(ENOENT)));
+                self.set_attr(&mut node, attr, &SetAttr::replayed(&attr, truncate))
+            },
+            Entry::Write(ref path, offset, ref data) => {
+                let node = try!(self.lookup_path(path).ok_or(Error::new(ENOENT)));
+                let handler = {
+                    let _ops = node.ops();
+                    let mut ops = _ops.borrow_mut();
+                    try!(ops.open(self, node.attr().ino, O_WRONLY as Mode).map_err(|err| err.with_ops(ops.name())))
+                };
+                let mut handler = handler.borrow_mut();
+                let result = handler.write(data, offset, data.len() as u64);
+                let _ = handler.release(0, false);
+                result.and(Ok(()))
+            },
+        }
+    }
+
+    // Directory snapshots are published in, made on first use
+    pub fn snapshot_dir(&mut self) -> Result<RcRef<Dir>> {
+        let path = Path::new(snapshot::SNAPSHOT_DIR);
+        match self.lookup_path(path) {
+            Some(Node::Dir(dir)) => return Ok(dir),
+            Some(Node::File(_)) => return Err(Error::new(ENOTDIR)),
+            None => {}
+        }
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let attr = FileAttr { ino: self.alloc_inode(), perm: 0o555, ..fileattr_new() };
+        let dir = RcRef!(Dir::new(name, attr, None, snapshot::FrozenOps::dir()));
+        let root = self.root.clone();
+        try!(self.link_node(&root, Node::Dir(dir.clone())));
+        Ok(dir)
+    }
+
+    // Freeze the subtree at `path` and publish it read-only as
+    // SNAPSHOT_DIR/<name>. File data is shared with the live tree
+    // until either side writes to it.
+    pub fn snapshot(&mut self, path: &Path, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(Error::new(EINVAL));
+        }
+        if self.snapshots.contains_key(name) {
+            return Err(Error::new(EEXIST));
+        }
+        let source = PathBuf::from("/").join(path);
+        let node = try!(self.lookup_path(&source).ok_or(Error::new(ENOENT)));
+        let tree = try!(snapshot::freeze(self, &node).ok_or(Error::new(EINVAL)));
+
+        let dir = try!(self.snapshot_dir());
+        if let Err(err) = snapshot::publish(self, &dir, name, &tree) {
+            let partial = dir.borrow().find_node(name).cloned();
+            if let Some(partial) = partial {
+                self.unlink_node(&dir, &partial);
+            }
+            return Err(err);
+        }
+
+        info!("snapshot: {:?} frozen as {}", source, name);
+        self.snapshots.insert(name.to_owned(), snapshot::Snapshot {
+            name: name.to_owned(),
+            source: source,
+            taken: time::get_time(),
+            tree: tree,
+        });
+        Ok(())
+    }
+
+    // The chunks the live tree no longer shares with the snapshot are
+    // released once its published copy is gone
+    pub fn drop_snapshot(&mut self, name: &str) -> Result<()> {
+        let snapshot = try!(self.snapshots.remove(name).ok_or(Error::new(ENOENT)));
+        let dir = try!(self.snapshot_dir());
+        let node = dir.borrow().find_node(name).cloned();
+        if let Some(node) = node {
+            self.unlink_node(&dir, &node);
+        }
+        self.usage.borrow_mut().release(snapshot::unshared(&snapshot.tree));
+        Ok(())
+    }
+
+    // Put the snapshot's source back into its frozen state. A rollback
+    // is not journaled; the tree is compacted into the snapshot file
+    // right away instead, so it is refused when there is a journal to
+    // replay over the old tree but no snapshot file.
+    pub fn rollback(&mut self, name: &str) -> Result<()> {
+        if self.journal.is_some() && self.snapshot_path.is_none() {
+            error!("rollback: {} would be undone by the journal on the next mount, \
+                    refused without a snapshot file", name);
+            return Err(Error::new(EPERM));
+        }
+        let snapshot = try!(self.snapshots.remove(name).ok_or(Error::new(ENOENT)));
+        let result = snapshot::rollback(self, &snapshot);
+        self.snapshots.insert(name.to_owned(), snapshot);
+        try!(result);
+        self.compact()
+    }
+
+    pub fn snapshots(&self) -> Vec<&snapshot::Snapshot> {
+        self.snapshots.values().collect()
+    }
+
+    pub fn usage(&self) -> RcRef<Usage> {
+        self.usage.clone()
+    }
+
+    // Without a limit, statfs reports the memory the host has available
+    // as free space
+    pub fn set_max_bytes(&mut self, max: Option<u64>) {
+        self.usage.borrow_mut().max_bytes = max;
+    }
+
+    pub fn set_max_inodes(&mut self, max: Option<u64>) {
+        self.max_inodes = max;
+    }
+
+    pub fn set_atime_policy(&mut self, policy: AtimePolicy) {
+        self.atime_policy = policy;
+    }
+
+    // Until a saved tree is loaded over it
+    pub fn set_root_perm(&mut self, perm: u16) {
+        self.root.borrow_mut().attr_mut().perm = perm;
+    }
+
+    // Refuse changes coming through FUSE with EROFS, before any ops hook
+    // runs. Nodes whose ops are writable_when_read_only() are exempt from
+    // open for writing, write and setattr, see handle_mknod() for
+    // creation. Access times are left alone.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn is_writable(&self, node: &Node) -> bool {
+        !self.read_only || node.ops().borrow().writable_when_read_only()
+    }
+
+    pub fn owner(&self) -> (u32, u32) {
+        self.owner
+    }
+
+    // Owner of the root and of the nodes created from now on
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.owner = (uid, gid);
+        let mut root = self.root.borrow_mut();
+        root.attr_mut().uid = uid;
+        root.attr_mut().gid = gid;
+    }
+
+    fn should_update_atime(&self, attr: &FileAttr, now: Timespec) -> bool {
+        if self.read_only {
+            return false;
+        }
+        match self.atime_policy {
+            AtimePolicy::Strict => true,
+            AtimePolicy::Noatime => false,
+            AtimePolicy::Relatime =>
+                attr.atime <= attr.mtime || attr.atime <= attr.ctime ||
+                now.sec - attr.atime.sec >= 24 * 60 * 60,
+        }
+    }
+
+    // Set the selected timestamps of `ino` to the current time
+    pub fn touch(&self, ino: Inode, what: u32) {
+        let mut node = match self.find_node(ino) {
+            Some(node) => node.clone(),
+            None => return,
+        };
+        let now = time::get_time();
+        let mut attr = node.attr();
+        if what & TOUCH_ATIME != 0 && self.should_update_atime(&attr, now) {
+            attr.atime = now;
+        }
+        if what & TOUCH_MTIME != 0 {
+            attr.mtime = now;
+        }
+        if what & TOUCH_CTIME != 0 {
+            attr.ctime = now;
+        }
+        node.set_attr(attr);
+    }
+
+    pub fn locks(&self) -> &lock::LockManager {
+        &self.locks
+    }
+
+    pub fn locks_mut(&mut self) -> &mut lock::LockManager {
+        &mut self.locks
+    }
+
+    // Pid of the process whose open() the ops is handling, e.g. to let
+    // the holder of an exclusive lock open the file again
+    pub fn opener_pid(&self) -> u32 {
+        self.opener_pid
+    }
+
+    // Retry waiting lock requests after locks went away, in the order
+    // they came; those that still conflict keep waiting
+    fn wake_lock_waiters(&mut self) {
+        let waiters = mem::replace(&mut self.lock_waiters, Vec::new());
+        for waiter in waiters.into_iter() {
+            match self.locks.set(waiter.ino, waiter.lock) {
+                Ok(_) => waiter.reply.ok(),
+                Err(ref err) if err.errno() == EAGAIN => self.lock_waiters.push(waiter),
+                Err(err) => waiter.reply.error(err.errno()),
+            }
+        }
+    }
+
+    // Drop the locks on a node that goes away; requests still waiting
+    // for one fail with ENOENT rather than waiting forever
+    fn forget_locks(&mut self, ino: Inode) {
+        self.locks.forget(ino);
+        let waiters = mem::replace(&mut self.lock_waiters, Vec::new());
+        for waiter in waiters.into_iter() {
+            if waiter.ino == ino {
+                waiter.reply.error(ENOENT);
+            } else {
+                self.lock_waiters.push(waiter);
+            }
+        }
+    }
+
+    // Waits of `owner` on `ino` end when it closes the file, as the
+    // kernel does on a signal: rust-fuse 0.2 drops FUSE_INTERRUPT
+    fn cancel_lock_waiters(&mut self, ino: Inode, owner: lock::LockOwner) {
+        let waiters = mem::replace(&mut self.lock_waiters, Vec::new());
+        for waiter in waiters.into_iter() {
+            if waiter.ino == ino && waiter.lock.owner == owner {
+                waiter.reply.error(EINTR);
+            } else {
+                self.lock_waiters.push(waiter);
+            }
+        }
+    }
+
+    // ENOSPC when the inode table is full,
+    // EDQUOT when the byte limit leaves no room for new data
+    fn check_capacity(&self) -> Result<()> {
+        if self.max_inodes.map_or(false, |max| self.inodes.len() as u64 >= max) {
+            return Err(Error::new(ENOSPC));
+        }
+        if self.usage.borrow().is_full() {
+            return Err(Error::new(EDQUOT));
+        }
+        Ok(())
+    }
+
     pub fn mknod(&mut self, parent_dir: &RcRef<Dir>, node: Node) -> Result<()> {
+        try!(self.check_capacity());
         try!(parent_dir.borrow_mut().mknod(node.clone()));
         self.register_node(node.clone());
 
         let _ops = node.ops();
         let mut ops = _ops.borrow_mut();
-        let result = ops.mknod(self, node.attr().ino, node.attr().perm);
+        let result = ops.mknod(self, node.attr().ino, node.attr().perm).map_err(|err| err.with_ops(ops.name()));
 
         if result.is_err() {
             let _ = parent_dir.borrow_mut().rmnod(&node.name(), node.attr().kind);
             self.unregister_node(node.attr().ino);
+        } else {
+            let parent = parent_dir.borrow().attr().ino;
+            self.touch(parent, TOUCH_MTIME | TOUCH_CTIME);
         }
         result
     }
 
+    // The parent is not borrowed while the hook runs: ops find their
+    // node's path through it
     pub fn rmnod(&mut self, _parent_dir: &RcRef<Dir>, path: &Path, kind: FileType) -> Result<()> {
-        let mut parent_dir = _parent_dir.borrow_mut();
         let name = path.to_str().unwrap();
-        let (inode, result) = {
-            let node = try!(parent_dir.find_node(name).ok_or(ENOENT));
-            let inode = node.attr().ino;
+        let node = try!(_parent_dir.borrow().find_node(name).cloned().ok_or(Error::new(ENOENT)));
+        let inode = node.attr().ino;
+        let result = {
             let _ops = node.ops();
             let mut ops = _ops.borrow_mut();
-            (inode, ops.rmnod(self, inode))
+            ops.rmnod(self, inode).map_err(|err| err.with_ops(ops.name()))
         };
         if result.is_ok() {
+            let mut parent_dir = _parent_dir.borrow_mut();
             let _ = parent_dir.rmnod(name, kind);
             self.unregister_node(inode);
+            self.forget_locks(inode);
+
+            let now = time::get_time();
+            parent_dir.attr_mut().mtime = now;
+            parent_dir.attr_mut().ctime = now;
         }
         result
     }
@@ -148,6 +970,8 @@ impl BasicFileSystem {
         let attr = FileAttr {
             ino: self.next_inode,
             perm: mode as Perm,
+            uid: self.owner.0,
+            gid: self.owner.1,
             ..fileattr_new()
         };
         let newdir = RcRef!(Dir::new(
@@ -169,6 +993,8 @@ impl BasicFileSystem {
         let attr = FileAttr {
             ino: self.next_inode,
             perm: mode as Perm,
+            uid: self.owner.0,
+            gid: self.owner.1,
             ..fileattr_new()
         };
         let newfile = RcRef!(File::new(
@@ -180,87 +1006,536 @@ impl BasicFileSystem {
 
         self.mknod(parent_dir, Node::File(newfile.clone())).and(Ok(newfile))
     }
+
+    // Move a node, replacing an existing target of the same kind
+    pub fn rename(&mut self, parent_dir: &RcRef<Dir>, name: &str,
+                  new_parent_dir: &RcRef<Dir>, newname: &str) -> Result<()> {
+        let mut node = try!(parent_dir.borrow().find_node(name).cloned().ok_or(Error::new(ENOENT)));
+        let kind = node.attr().kind;
+
+        // A directory cannot move below itself
+        if node.is_dir() {
+            let ino = node.attr().ino;
+            let mut ancestor = Some(new_parent_dir.borrow().attr().ino);
+            while let Some(dir) = ancestor {
+                if dir == ino {
+                    return Err(Error::new(EINVAL));
+                }
+                ancestor = self.find_node(dir).and_then(|node| node.parent());
+            }
+        }
+
+        let target = new_parent_dir.borrow().find_node(newname).cloned();
+        if let Some(target) = target {
+            if target.attr().ino == node.attr().ino {
+                return Ok(());
+            }
+            if target.attr().kind != kind {
+                return Err(Error::new(if kind == FileType::Directory { ENOTDIR } else { EISDIR }));
+            }
+            if target.is_dir() && !target.to_dir().borrow().nodes().is_empty() {
+                return Err(Error::new(ENOTEMPTY));
+            }
+            try!(self.rmnod(new_parent_dir, newname.as_ref(), kind));
+        }
+
+        {
+            let _ops = node.ops();
+            let mut ops = _ops.borrow_mut();
+            let newparent = new_parent_dir.borrow().attr().ino;
+            try!(ops.rename(self, node.attr().ino, newparent, newname).map_err(|err| err.with_ops(ops.name())));
+        }
+        try!(parent_dir.borrow_mut().rmnod(name, kind));
+        node.set_name(newname);
+        try!(new_parent_dir.borrow_mut().mknod(node.clone()));
+
+        let parent = parent_dir.borrow().attr().ino;
+        let newparent = new_parent_dir.borrow().attr().ino;
+        self.touch(parent, TOUCH_MTIME | TOUCH_CTIME);
+        self.touch(newparent, TOUCH_MTIME | TOUCH_CTIME);
+        self.touch(node.attr().ino, TOUCH_CTIME);
+        Ok(())
+    }
+
+    // Replace the attributes of `node`. The inode number, kind and link
+    // count are kept; `truncate` resizes the data to attr.size first.
+    // `attr` is the node's attr with what `set` asks for applied
+    pub fn set_attr(&mut self, node: &mut Node, attr: FileAttr, set: &SetAttr) -> Result<()> {
+        let old = node.attr();
+        {
+            let _ops = node.ops();
+            let mut ops = _ops.borrow_mut();
+            if set.size.is_some() {
+                try!(ops.truncate(self, old.ino, attr.size).map_err(|err| err.with_ops(ops.name())));
+            }
+            try!(ops.setattr(self, old.ino, set).map_err(|err| err.with_ops(ops.name())));
+        }
+        node.set_attr(FileAttr {
+            ino: old.ino,
+            kind: old.kind,
+            nlink: old.nlink,
+            blocks: old.blocks,
+            ..attr
+        });
+        Ok(())
+    }
+
+    // How long the kernel may cache the entry and the attributes of
+    // `ino`, as its ops declare
+    pub fn cache_ttl(&self, ino: Inode) -> (Timespec, Timespec) {
+        let ops = match self.find_node(ino) {
+            Some(node) => node.ops(),
+            None => return (NO_TTL, NO_TTL),
+        };
+        let ops = ops.borrow();
+        let ttl = (ops.entry_ttl(), ops.attr_ttl());
+        ttl
+    }
 }
 
 impl Drop for BasicFileSystem {
     fn drop(&mut self) {
-        let names: Vec<_> = self.ops.iter()
-            .map(|&(_, ref t)| t.borrow().name().to_owned()).collect();
-        for ref ops_name in names {
-            self.unregister_ops(ops_name);
+        let result = self.teardown();
+        if let Some(ref report) = self.teardown_report {
+            *report.lock().unwrap() = result;
         }
     }
 }
 
-const TTL: Timespec = Timespec { sec: 0, nsec: 0 };
+const NO_TTL: Timespec = Timespec { sec: 0, nsec: 0 };
 
-macro_rules! find_node_or_error {
-    ($dir:expr, $key:expr, $reply:expr) => {
-        match $dir.find_node($key) {
-            Some(node) => node.clone(),
-            None => { $reply.error(ENOENT); return; }
+// An entry listed by handle_readdir(): inode, kind and name
+pub type DirEntry = (Inode, FileType, String);
+
+// Fields of a setattr request, None where unchanged
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SetAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+    pub crtime: Option<Timespec>,
+    pub chgtime: Option<Timespec>,
+    pub flags: Option<u32>,
+}
+
+impl SetAttr {
+    // Every field of a journaled attr, the size only if it was truncated
+    fn replayed(attr: &FileAttr, truncate: bool) -> SetAttr {
+        SetAttr {
+            mode: Some(attr.perm as u32),
+            uid: Some(attr.uid),
+            gid: Some(attr.gid),
+            size: if truncate { Some(attr.size) } else { None },
+            atime: Some(attr.atime),
+            mtime: Some(attr.mtime),
+            crtime: Some(attr.crtime),
+            chgtime: Some(attr.ctime),
+            flags: Some(attr.flags),
         }
     }
 }
 
-macro_rules! get_handler_for {
-    ($fs:expr, $fh:expr, $reply:expr) => {
-        match $fs.openfds.get(&$fh) {
-            Some(handler) => handler,
-            None => { $reply.error(EBADF); return; }
+// Requests as the kernel makes them, by inode and handle. The FUSE
+// callbacks below only turn their results into replies, so anything
+// driving these (e.g. harness::Harness) runs the same logic.
+impl BasicFileSystem {
+    fn node(&mut self, ino: Inode) -> Result<Node> {
+        match self.find_node(ino).cloned() {
+            Some(node) => Ok(node),
+            None => self.revive(ino),
+        }
+    }
+
+    fn handler(&self, fh: Handle) -> Result<RcRefBox<ops::OpenHandler>> {
+        self.openfds.get(&fh).map(|file| file.handler.clone()).ok_or(Error::new(EBADF))
+    }
+
+    // Load the saved tree and replay the journal over it
+    pub fn handle_init(&mut self) -> Result<()> {
+        if let Some(path) = self.snapshot_path.clone() {
+            self.journal_seq = try!(persist::restore(self, &path).map_err(|err| err.with_path(&path)));
+        }
+        if let Some(path) = self.journal_path.clone() {
+            let entries = try!(journal::read(&path).map_err(|err| err.with_path(&path)));
+            let replayed = !entries.is_empty();
+            self.replay(entries);
+            self.journal = Some(try!(journal::Journal::open(&path)
+                                     .map_err(|err| err.with_path(&path))));
+            if replayed {
+                try!(self.compact());
+            }
+        }
+        self.mounted = true;
+        Ok(())
+    }
+
+    pub fn handle_lookup(&mut self, parent: Inode, name: &Path) -> Result<FileAttr> {
+        let node = try!(self.node(parent));
+        let entry = try!(self.lookup(node.to_dir(), name.to_str().unwrap()));
+        self.getattr(&entry)
+    }
+
+    pub fn handle_getattr(&mut self, ino: Inode) -> Result<FileAttr> {
+        let node = try!(self.node(ino));
+        self.getattr(&node)
+    }
+
+    pub fn handle_readlink(&mut self, ino: Inode) -> Result<Vec<u8>> {
+        let node = try!(self.node(ino));
+        let _ops = node.ops();
+        let mut ops = _ops.borrow_mut();
+        ops.readlink(self, ino).map_err(|err| err.with_ops(ops.name()))
+    }
+
+    pub fn handle_setattr(&mut self, ino: Inode, set: &SetAttr) -> Result<FileAttr> {
+        let mut node = try!(self.node(ino));
+        if !self.is_writable(&node) {
+            return Err(Error::new(EROFS));
+        }
+        let mut attr = node.attr();
+
+        let now = time::get_time();
+        attr.ctime = now;
+        if set.size.is_some() {
+            attr.mtime = now;
+        }
+
+        set_if_some!(attr.size, set.size);
+        set_if_some!(attr.atime, set.atime);
+        set_if_some!(attr.mtime, set.mtime);
+        set_if_some!(attr.ctime, set.chgtime);
+        set_if_some!(attr.crtime, set.crtime);
+        set_if_some!(attr.perm, set.mode.map(|n| n as u16));
+        set_if_some!(attr.uid, set.uid);
+        set_if_some!(attr.gid, set.gid);
+        set_if_some!(attr.flags, set.flags);
+
+        try!(self.set_attr(&mut node, attr, set));
+        if self.is_journaled(&node) {
+            let path = get_path(self, &node);
+            try!(self.log(Entry::Setattr(path, attr, set.size.is_some())));
+        }
+        Ok(attr)
+    }
+
+    pub fn handle_readdir(&mut self, ino: Inode) -> Result<Vec<DirEntry>> {
+        let parent_dir = try!(self.node(ino));
+        if !parent_dir.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        let dir = parent_dir.to_dir().clone();
+        self.expire(&dir);
+        {
+            let _ops = parent_dir.ops();
+            let mut ops = _ops.borrow_mut();
+            let now = time::get_time();
+            match ops.populate_ttl() {
+                None => try!(ops.readdir(self, ino).map_err(|err| err.with_ops(ops.name()))),
+                Some(_) if dir.borrow().is_listed(now) => {},
+                Some(ttl) => {
+                    let before: Vec<String> = dir.borrow().nodes().keys().cloned().collect();
+                    try!(ops.readdir(self, ino).map_err(|err| err.with_ops(ops.name())));
+                    let mut dir = dir.borrow_mut();
+                    let added: Vec<String> = dir.nodes().keys()
+                        .filter(|name| !before.contains(name))
+                        .cloned().collect();
+                    for name in added.iter() {
+                        dir.cache(name, now + ttl);
+                    }
+                    dir.set_listed(Some(now + ttl));
+                },
+            }
+        }
+        let entries: Vec<DirEntry> = dir.borrow().nodes().iter()
+            .map(|(name, node)| (node.attr().ino, node.attr().kind, name.clone()))
+            .collect();
+        self.touch(ino, TOUCH_ATIME);
+        Ok(entries)
+    }
+
+    pub fn handle_mkdir(&mut self, parent: Inode, name: &Path, mode: Mode) -> Result<FileAttr> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
         }
+        let parent_dir = try!(self.node(parent));
+        let path = get_path(self, &parent_dir).join(name);
+        let dir = try!(self.mkdir(parent_dir.to_dir(), name, mode));
+        if self.is_journaled(&Node::Dir(dir.clone())) {
+            try!(self.log(Entry::Mkdir(path, mode)));
+        }
+        let attr = *dir.borrow().attr();
+        Ok(attr)
     }
+
+    pub fn handle_rmdir(&mut self, parent: Inode, name: &Path) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        let parent_dir = try!(self.node(parent));
+        let path = get_path(self, &parent_dir).join(name);
+        let journaled = self.is_journaled_entry(&parent_dir, name);
+        try!(self.rmnod(parent_dir.to_dir(), name, FileType::Directory));
+        if journaled {
+            try!(self.log(Entry::Rmnod(path, true)));
+        }
+        Ok(())
+    }
+
+    // Allowed on a read-only filesystem when both the directory's ops
+    // and those of the new file are writable_when_read_only(), for ops
+    // that act on creation such as a socket connecting
+    pub fn handle_mknod(&mut self, parent: Inode, name: &Path, mode: Mode) -> Result<FileAttr> {
+        let parent_dir = try!(self.node(parent));
+        let path = get_path(self, &parent_dir).join(name);
+        if self.read_only {
+            let ops = self.get_ops(&path, FileType::RegularFile);
+            if !self.is_writable(&parent_dir) || !ops.borrow().writable_when_read_only() {
+                return Err(Error::new(EROFS));
+            }
+        }
+        let file = try!(self.mkfile(parent_dir.to_dir(), name, mode));
+        if self.is_journaled(&Node::File(file.clone())) {
+            try!(self.log(Entry::Mkfile(path, mode)));
+        }
+        let attr = *file.borrow().attr();
+        Ok(attr)
+    }
+
+    pub fn handle_unlink(&mut self, parent: Inode, name: &Path) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        let parent_dir = try!(self.node(parent));
+        let path = get_path(self, &parent_dir).join(name);
+        let journaled = self.is_journaled_entry(&parent_dir, name);
+        try!(self.rmnod(parent_dir.to_dir(), name, FileType::RegularFile));
+        if journaled {
+            try!(self.log(Entry::Rmnod(path, false)));
+        }
+        Ok(())
+    }
+
+    pub fn handle_rename(&mut self, parent: Inode, name: &Path,
+                         newparent: Inode, newname: &Path) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        let parent_dir = try!(self.node(parent));
+        let new_parent_dir = try!(self.node(newparent));
+        let from = get_path(self, &parent_dir).join(name);
+        let to = get_path(self, &new_parent_dir).join(newname);
+
+        let was_journaled = self.is_journaled_entry(&parent_dir, name);
+        let name = name.to_str().unwrap();
+        let newname = newname.to_str().unwrap();
+        try!(self.rename(parent_dir.to_dir(), name, new_parent_dir.to_dir(), newname));
+        // A node moved out of what is journaled is gone as far as
+        // replay goes; one moved in is saved by the next compaction
+        let journaled = self.is_journaled_entry(&new_parent_dir, newname.as_ref());
+        match (was_journaled, journaled) {
+            (true, true) => self.log(Entry::Rename(from, to)),
+            (true, false) => {
+                let is_dir = new_parent_dir.to_dir().borrow().find_node(newname)
+                    .map_or(false, |node| node.is_dir());
+                self.log(Entry::Rmnod(from, is_dir))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn handle_open(&mut self, ino: Inode, flags: Mode, pid: u32) -> Result<Handle> {
+        let node = try!(self.node(ino));
+        let writing = flags as c_int & ops::ACCMODE != O_RDONLY || flags as c_int & O_TRUNC != 0;
+        if writing && !self.is_writable(&node) {
+            return Err(Error::new(EROFS));
+        }
+        if node.attr().kind == FileType::Directory {
+            return Err(Error::new(EBADF));
+        }
+
+        let handle = self.next_handle;
+        self.opener_pid = pid;
+        let handler = {
+            let _ops = node.ops();
+            let mut ops = _ops.borrow_mut();
+            try!(ops.open(self, ino, flags).map_err(|err| err.with_ops(ops.name())))
+        };
+
+        info!("open: fullpath={:?} handle={} handler={}",
+            get_path(self, &node), handle, handler.borrow().name());
+
+        self.openfds.insert(handle, OpenFile { ino: ino, handler: handler });
+        self.next_handle += 1;
+        Ok(handle)
+    }
+
+    // FOPEN_* flags of the reply to open, as the handler asks
+    pub fn fopen_flags(&self, fh: Handle) -> u32 {
+        let handler = match self.openfds.get(&fh) {
+            Some(file) => file.handler.clone(),
+            None => return FOPEN_DIRECT_IO,
+        };
+        let handler = handler.borrow();
+        let flags = if handler.direct_io() {
+            FOPEN_DIRECT_IO
+        } else if handler.keep_cache() {
+            FOPEN_KEEP_CACHE
+        } else {
+            0
+        };
+        flags
+    }
+
+    pub fn handle_read(&mut self, ino: Inode, fh: Handle, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let handler = try!(self.handler(fh));
+        let data = try!(handler.borrow_mut().read(offset, size as u64));
+        self.touch(ino, TOUCH_ATIME);
+        Ok(data)
+    }
+
+    pub fn handle_write(&mut self, ino: Inode, fh: Handle, offset: u64, data: &[u8]) -> Result<u32> {
+        let node = try!(self.node(ino));
+        if !self.is_writable(&node) {
+            return Err(Error::new(EROFS));
+        }
+
+        let handler = try!(self.handler(fh));
+        let size = try!(handler.borrow_mut().write(data, offset, data.len() as u64));
+        try!(handler.borrow_mut().commit(self));
+        self.touch(ino, TOUCH_MTIME | TOUCH_CTIME);
+        if node.ops().borrow().journal_writes() && self.is_journaled(&node) {
+            let path = get_path(self, &node);
+            try!(self.log(Entry::Write(path, offset, data.to_vec())));
+        }
+        Ok(size as u32)
+    }
+
+    pub fn handle_release(&mut self, ino: Inode, fh: Handle, flags: u32,
+                          lock_owner: u64, flush: bool) -> Result<()> {
+        let handler = try!(self.handler(fh));
+        try!(handler.borrow_mut().release(flags, flush));
+        self.openfds.remove(&fh);
+        self.locks.release(ino, lock_owner);
+        self.cancel_lock_waiters(ino, lock_owner);
+        self.wake_lock_waiters();
+        info!("release: handle={}", fh);
+        Ok(())
+    }
+
+    // Called on every close(2) of a descriptor for the file
+    pub fn handle_flush(&mut self, ino: Inode, fh: Handle, lock_owner: u64) -> Result<()> {
+        try!(self.handler(fh));
+        self.locks.flush(ino, lock_owner);
+        self.cancel_lock_waiters(ino, lock_owner);
+        self.wake_lock_waiters();
+        Ok(())
+    }
+
+    // Take, change or drop a POSIX lock, or a flock lock with `flock`.
+    // EAGAIN if it conflicts with a lock of another owner.
+    pub fn handle_setlk(&mut self, ino: Inode, lock_owner: u64, start: u64, end: u64,
+                        typ: u32, pid: u32, flock: bool) -> Result<()> {
+        try!(self.node(ino));
+        let lock = lock::Lock {
+            owner: lock_owner, start: start, end: end, typ: typ, pid: pid, flock: flock,
+        };
+        try!(self.locks.set(ino, lock));
+        self.wake_lock_waiters();
+        Ok(())
+    }
+}
+
+// MemAvailable of /proc/meminfo, in bytes
+fn available_memory() -> Option<u64> {
+    let mut meminfo = String::new();
+    if hostfs::File::open(MEMINFO).and_then(|mut f| f.read_to_string(&mut meminfo)).is_err() {
+        return None;
+    }
+    meminfo.lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
 }
 
+// NOTE::
+//  rust-fuse 0.2 does not expose notify_inval_inode or
+//  notify_inval_entry, so the kernel cannot be told to drop what it
+//  caches. Nodes that change
+//  behind its back keep zero TTLs; see ops::STATIC_TTL for the others.
+//  Nor does it offer FUSE_FLOCK_LOCKS at init, so the kernel keeps
+//  flock(2) locks on the mount to itself and every setlk() that reaches
+//  us is a POSIX lock (its setlk() has no lk_flags to tell otherwise).
+//  handle_setlk() takes flock locks from in-process callers only.
+
 impl fuse::Filesystem for BasicFileSystem {
-    fn init (&mut self, _req: &Request) -> Result<()> { Ok(()) }
-    fn destroy (&mut self, _req: &Request) {}
+    fn init (&mut self, _req: &Request) -> result::Result<(), c_int> {
+        let result = self.handle_init();
+        if let Some(mut report) = self.mount_report.take() {
+            report(&result);
+        }
+        result.map_err(|err| self.fail("init", fuse::FUSE_ROOT_ID, err))
+    }
+
+    // Once saved, teardown() on drop has nothing left to save; after a
+    // failure it tries again and reports the outcome
+    fn destroy (&mut self, _req: &Request) {
+        match self.compact() {
+            Ok(()) => self.mounted = false,
+            Err(err) => error!("destroy: cannot save snapshot to {:?} ({})", self.snapshot_path, err),
+        }
+    }
 
     fn lookup (&mut self, _req: &Request, parent: Inode, name: &Path, reply: ReplyEntry) {
-        let node = find_node_or_error!(self, parent, reply);
-        let parent_dir = node.to_dir().borrow();
-        let entry = find_node_or_error!(parent_dir, name.to_str().unwrap(), reply);
-        reply.entry(&TTL, &entry.attr(), 0);
+        match self.handle_lookup(parent, name) {
+            Ok(ref attr) => reply.entry(&self.cache_ttl(attr.ino).0, attr, 0),
+            Err(err) => reply.error(self.fail("lookup", parent, err))
+        }
     }
 
     fn getattr (&mut self, _req: &Request, ino: Inode, reply: ReplyAttr) {
-        let node = find_node_or_error!(self, ino, reply);
-        let _ops = node.ops();
-        let mut ops = _ops.borrow_mut();
-        match ops.getattr(node) {
-            Ok(ref attr) => reply.attr(&TTL, attr),
-            Err(err) => reply.error(err)
+        match self.handle_getattr(ino) {
+            Ok(ref attr) => reply.attr(&self.cache_ttl(attr.ino).1, attr),
+            Err(err) => reply.error(self.fail("getattr", ino, err))
+        }
+    }
+
+    fn readlink (&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.handle_readlink(ino) {
+            Ok(target) => reply.data(&target),
+            Err(err) => reply.error(self.fail("readlink", ino, err))
         }
     }
 
     fn setattr (&mut self, _req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>,
         size: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>, _fh: Option<u64>, crtime: Option<Timespec>,
         chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, flags: Option<u32>, reply: ReplyAttr) {
-        let mut node = find_node_or_error!(self, ino, reply);
-        let mut attr = node.attr();
-
-        set_if_some!(attr.size, size);
-        set_if_some!(attr.atime, atime);
-        set_if_some!(attr.mtime, mtime);
-        set_if_some!(attr.ctime, chgtime);
-        set_if_some!(attr.crtime, crtime);
-        set_if_some!(attr.perm, mode.map(|n| n as u16));
-        set_if_some!(attr.uid, uid);
-        set_if_some!(attr.gid, gid);
-        set_if_some!(attr.flags, flags);
-
-        reply.attr(&TTL, &attr);
-        node.set_attr(attr);
+        let set = SetAttr {
+            mode: mode, uid: uid, gid: gid, size: size,
+            atime: atime, mtime: mtime, crtime: crtime, chgtime: chgtime,
+            flags: flags,
+        };
+        match self.handle_setattr(ino, &set) {
+            Ok(ref attr) => reply.attr(&self.cache_ttl(attr.ino).1, attr),
+            Err(err) => reply.error(self.fail("setattr", ino, err))
+        }
     }
 
     fn readdir (&mut self, _req: &Request, ino: Inode, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
-        let parent_dir = find_node_or_error!(self, ino, reply);
         if offset == 0 {
+            let entries = match self.handle_readdir(ino) {
+                Ok(entries) => entries,
+                Err(err) => { reply.error(self.fail("readdir", ino, err)); return }
+            };
+
             reply.add(1, 0, FileType::Directory, ".");
             reply.add(1, 1, FileType::Directory, "..");
             let mut i = 2;
-            for (ref name, ref node) in parent_dir.to_dir().borrow().nodes() {
-                reply.add(node.attr().ino, i, node.attr().kind, name);
+            for &(ino, kind, ref name) in entries.iter() {
+                reply.add(ino, i, kind, name);
                 i += 1;
             }
         }
@@ -268,112 +1543,187 @@ impl fuse::Filesystem for BasicFileSystem {
     }
 
     fn mkdir (&mut self, _req: &Request, parent: Inode, name: &Path, mode: Mode, reply: ReplyEntry) {
-        let parent_dir = find_node_or_error!(self, parent, reply);
-        let newdir = self.mkdir(parent_dir.to_dir(), name, mode);
-        match newdir {
-            Ok(dir) => reply.entry(&TTL, dir.borrow().attr(), 0),
-            Err(err) => reply.error(err)
+        match self.handle_mkdir(parent, name, mode) {
+            Ok(ref attr) => reply.entry(&self.cache_ttl(attr.ino).0, attr, 0),
+            Err(err) => reply.error(self.fail("mkdir", parent, err))
         }
     }
 
     fn rmdir(&mut self, _req: &Request, parent: Inode, name: &Path, reply: ReplyEmpty) {
-        let parent_dir = find_node_or_error!(self, parent, reply);
-        match self.rmnod(parent_dir.to_dir(), name, FileType::Directory) {
+        match self.handle_rmdir(parent, name) {
             Ok(_) => reply.ok(),
-            Err(err) => reply.error(err)
+            Err(err) => reply.error(self.fail("rmdir", parent, err))
         }
     }
 
     fn mknod(&mut self, _req: &Request, parent: Inode, name: &Path, mode: Mode, _rdev: u32, reply: ReplyEntry) {
-        let parent_dir = find_node_or_error!(self, parent, reply);
-        let newfile = self.mkfile(parent_dir.to_dir(), name, mode);
-        match newfile {
-            Ok(file) => reply.entry(&TTL, file.borrow().attr(), 0),
-            Err(err) => reply.error(err)
+        match self.handle_mknod(parent, name, mode) {
+            Ok(ref attr) => reply.entry(&self.cache_ttl(attr.ino).0, attr, 0),
+            Err(err) => reply.error(self.fail("mknod", parent, err))
         }
     }
 
     fn unlink(&mut self, _req: &Request, parent: Inode, name: &Path, reply: ReplyEmpty) {
-        let parent_dir = find_node_or_error!(self, parent, reply);
-        match self.rmnod(parent_dir.to_dir(), name, FileType::RegularFile) {
+        match self.handle_unlink(parent, name) {
             Ok(_) => reply.ok(),
-            Err(err) => reply.error(err)
+            Err(err) => reply.error(self.fail("unlink", parent, err))
         }
     }
 
     fn rename(&mut self, _req: &Request, parent: u64, name: &Path, newparent: u64, newname: &Path, reply: ReplyEmpty) {
-        let name = name.to_str().unwrap();
-        let newname = newname.to_str().unwrap();
-        let parent_dir = find_node_or_error!(self, parent, reply);
-        let new_parent_dir = find_node_or_error!(self, newparent, reply);
-        let mut node = find_node_or_error!(parent_dir.to_dir().borrow(), name, reply);
+        match self.handle_rename(parent, name, newparent, newname) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(self.fail("rename", parent, err))
+        }
+    }
 
-        node.set_name(newname);
-        let _ = parent_dir.to_dir().borrow_mut().rmnod(name, node.attr().kind);
-        let _ = new_parent_dir.to_dir().borrow_mut().mknod(node);
+    fn open(&mut self, req: &Request, ino: Inode, flags: Mode, reply: ReplyOpen) {
+        match self.handle_open(ino, flags, req.pid()) {
+            Ok(handle) => reply.opened(handle, self.fopen_flags(handle)),
+            Err(err) => reply.error(self.fail("open", ino, err))
+        }
+    }
 
-        reply.ok();
+    fn read (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        match self.handle_read(ino, fh, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(self.fail("read", ino, err))
+        }
     }
 
-    fn open(&mut self, _req: &Request, ino: Inode, flags: Mode, reply: ReplyOpen) {
-        let node = find_node_or_error!(self, ino, reply);
-        if node.attr().kind != FileType::Directory {
-            let handle = self.next_handle;
+    fn write (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        match self.handle_write(ino, fh, offset, data) {
+            Ok(size) => reply.written(size),
+            Err(err) => reply.error(self.fail("write", ino, err))
+        }
+    }
 
-            let _ops = node.ops();
-            let mut ops = _ops.borrow_mut();
-            let handler = match ops.open(self, ino, flags as Perm) {
-                Ok(fh) => fh,
-                Err(err) => { reply.error(err); return }
+    fn release (&mut self, _req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+        match self.handle_release(ino, fh, flags, lock_owner, flush) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(self.fail("release", ino, err))
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        match self.handle_flush(ino, fh, lock_owner) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(self.fail("flush", ino, err))
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let bsize = BLOCK_SIZE as u64;
+        let (used, capacity) = {
+            let usage = self.usage.borrow();
+            // Data lives in memory, so without a limit the space left
+            // is what the host has available
+            let capacity = match usage.max_bytes() {
+                Some(max) => max,
+                None => usage.bytes() + available_memory().unwrap_or(0),
             };
+            (usage.bytes(), capacity)
+        };
+        let blocks = (capacity + bsize - 1) / bsize;
+        let bfree = blocks - cmp::min(blocks, (used + bsize - 1) / bsize);
 
-            info!("open: fullpath={:?} handle={} handler={}",
-                get_path(self, &node), handle, handler.borrow().name());
+        let files = self.inodes.len() as u64;
+        let (total_files, ffree) = match self.max_inodes {
+            Some(max) => (max, if max > files { max - files } else { 0 }),
+            None => (files + u32::max_value() as u64, u32::max_value() as u64),
+        };
 
-            self.openfds.insert(handle, handler);
-            self.next_handle += 1;
-            reply.opened(handle, flags | FOPEN_DIRECT_IO);
-        } else {
-            reply.error(EBADF);
-        }
+        reply.statfs(blocks, bfree, bfree, total_files, ffree, BLOCK_SIZE, NAME_MAX, BLOCK_SIZE);
     }
 
-    fn read (&mut self, _req: &Request, _ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
-        let _handler = get_handler_for!(self, fh, reply);
-        let mut handler = _handler.borrow_mut();
-        match handler.read(offset, size as u64) {
-            Ok(data) => reply.data(&data),
-            Err(err) => reply.error(err)
+    fn getlk(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64,
+        start: u64, end: u64, typ: u32, _pid: u32, reply: ReplyLock) {
+        match self.locks.getlk(ino, lock_owner, start, end, typ) {
+            Some(l) => reply.locked(l.start, l.end, l.typ, l.pid),
+            None => reply.locked(start, end, lock::F_UNLCK, 0),
         }
     }
 
-    fn write (&mut self, _req: &Request, _ino: u64, fh: u64, offset: u64, data: &[u8], _flags: u32, reply: ReplyWrite) {
-        let handler = get_handler_for!(self, fh, reply);
-        let result = handler.borrow_mut().write(data, offset, data.len() as u64);
-        match result {
-            Ok(size) => reply.written(size as u32),
-            Err(err) => reply.error(err)
+    // F_SETLKW is answered once the lock is taken, from the request
+    // that drops the conflicting one. Always a POSIX lock, see the NOTE
+    // above.
+    fn setlk(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64,
+        start: u64, end: u64, typ: u32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        match self.handle_setlk(ino, lock_owner, start, end, typ, pid, false) {
+            Ok(_) => reply.ok(),
+            Err(ref err) if sleep && err.errno() == EAGAIN => {
+                let lock = lock::Lock {
+                    owner: lock_owner, start: start, end: end, typ: typ, pid: pid, flock: false,
+                };
+                debug!("setlk: {:?} on inode {} waits", lock, ino);
+                self.lock_waiters.push(LockWaiter { ino: ino, lock: lock, reply: reply });
+            },
+            Err(err) => reply.error(self.fail("setlk", ino, err))
         }
     }
+}
 
-    fn release (&mut self, _req: &Request, _ino: u64, fh: u64, flags: u32, _lock_owner: u64, flush: bool, reply: ReplyEmpty) {
-        let result =
-            get_handler_for!(self, fh, reply).borrow_mut().release(flags, flush);
-        match result {
-            Ok(_) => {
-                self.openfds.remove(&fh);
-                reply.ok();
-                info!("release: handle={}", fh);
-            },
-            Err(err) => reply.error(err)
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use super::BasicFileSystem;
+    use super::fuse::FileAttr;
+    use super::libc::consts::os::posix88::*;
+    use super::time::Duration;
+    use common::{RcRefBox, Result, Error};
+    use fs::{Inode, Node, Dir, File, fileattr_new};
+    use ops::Operations;
+    use harness::Harness;
+
+    // Serves a file "f" in its directory that expires as soon as it is
+    // looked up
+    struct Volatile;
+
+    impl Operations for Volatile {
+        fn name(&self) -> &str { "volatile" }
+        fn new_ops(&self) -> RcRefBox<Operations> { RcRefBox!(Volatile) }
+        fn populate_ttl(&self) -> Option<Duration> { Some(Duration::zero()) }
+        fn lookup(&mut self, fs: &mut BasicFileSystem, ino: Inode, name: &str) -> Result<Option<Node>> {
+            if name != "f" {
+                return Ok(None);
+            }
+            let parent_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).to_dir().clone();
+            let attr = FileAttr { ino: fs.alloc_inode(), perm: 0o444, ..fileattr_new() };
+            let node = Node::File(RcRef!(File::new(name, attr, None, self.new_ops())));
+            try!(fs.link_node(&parent_dir, node.clone()));
+            Ok(Some(node))
         }
     }
 
-    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        reply.ok();
+    #[test]
+    fn read_only() {
+        let mut h = Harness::new();
+        h.mknod("/f").unwrap();
+        h.fs().set_read_only(true);
+        assert_eq!(h.mkdir("/a").err(), Some(EROFS));
+        assert_eq!(h.open("/f", O_WRONLY).err(), Some(EROFS));
+        assert!(h.open("/f", O_RDONLY).is_ok());
     }
 
-    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.ok();
+    // The kernel keeps using the inode of an expired node until it
+    // forgets it
+    #[test]
+    fn expired_nodes_keep_inode() {
+        let mut h = Harness::new();
+        let root = h.fs().root();
+        let attr = FileAttr { ino: h.fs().alloc_inode(), perm: 0o755, ..fileattr_new() };
+        let dir = Node::Dir(RcRef!(Dir::new("v", attr, None, Volatile.new_ops())));
+        h.fs().link_node(&root, dir).unwrap();
+
+        let ino = h.lookup("/v/f").unwrap().ino;
+        assert_eq!(h.lookup("/v/g").err(), Some(ENOENT));  // expires f
+        assert!(h.fs().find_node(ino).is_none());
+        assert_eq!(h.fs().handle_getattr(ino).map(|attr| attr.ino).ok(), Some(ino));
+        assert_eq!(h.lookup("/v/f").unwrap().ino, ino);
     }
 }
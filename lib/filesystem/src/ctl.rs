@@ -0,0 +1,340 @@
+extern crate libc;
+
+use std::str;
+use std::result;
+use std::rc::Rc;
+use std::cell::RefCell;
+use self::libc::consts::os::posix88::*; /* POSIX errno */
+
+use fs::*;
+use common::*;
+use ops::{Operations, OpenHandler};
+use synthetic::read_at;
+use core::BasicFileSystem;
+
+//
+// Control files
+//
+// A ctl file takes one command per line. Lines are split into words at
+// white space; a word may be quoted with single quotes, '' standing for
+// a quote inside them. The first word picks the command:
+//
+//  let commands = CtlCommands::new("SnapshotOps.CtlHandler")
+//      .command("drop <name>", |fs, args| fs.drop_snapshot(args[0]).map(|_| None))
+//      .status(|fs| list_snapshots(fs));
+//
+// The usage string gives the arguments a command takes: "<arg>" is
+// required, "[arg]" optional, and "[args...]" takes any number more.
+// A failed command fails the write with its errno and leaves its error
+// string to be read back, like Plan 9's errstr. Each handle has its own,
+// so one client cannot see or clear the errors of another.
+//
+// Reading a handle gives the output of its last command, or the error
+// string if that command failed. Before any command, it gives the status.
+// A line longer than MAX_LINE fails the write with E2BIG.
+//
+
+// Longest command line a handle buffers
+pub const MAX_LINE: usize = 4096;
+
+pub type CommandFn = Fn(&mut BasicFileSystem, &[&str]) -> Result<Option<String>>;
+pub type StatusFn = Fn(&mut BasicFileSystem) -> String;
+
+struct Command {
+    name: String,
+    usage: String,
+    min_args: usize,
+    max_args: Option<usize>,    // None for any number
+    run: Box<CommandFn>,
+}
+
+impl Command {
+    fn new(usage: &str, run: Box<CommandFn>) -> Command {
+        let mut words = usage.split_whitespace();
+        let name = words.next().unwrap_or("").to_owned();
+        let (mut min_args, mut max_args) = (0, Some(0));
+        for word in words {
+            if word.ends_with("...]") || word.ends_with("...>") {
+                max_args = None;
+            } else {
+                max_args = max_args.map(|n| n + 1);
+            }
+            if word.starts_with("<") {
+                min_args += 1;
+            }
+        }
+        Command {
+            name: name, usage: usage.to_owned(),
+            min_args: min_args, max_args: max_args, run: run,
+        }
+    }
+
+    fn accepts(&self, nargs: usize) -> bool {
+        nargs >= self.min_args && self.max_args.map_or(true, |max| nargs <= max)
+    }
+}
+
+// The commands of a ctl file, shared by its handles
+#[derive(Clone)]
+pub struct CtlCommands {
+    name: String,
+    commands: Vec<Rc<Command>>,
+    status: Option<Rc<Box<StatusFn>>>,
+}
+
+impl CtlCommands {
+    // `name` is that of the handlers, also used in logs
+    pub fn new(name: &str) -> CtlCommands {
+        CtlCommands {
+            name: name.to_owned(),
+            commands: Vec::new(),
+            status: None,
+        }
+    }
+
+    // `usage` is the command name followed by its arguments. `run` gets
+    // the arguments only and may return text for the handle to read.
+    pub fn command<F>(mut self, usage: &str, run: F) -> CtlCommands
+        where F: Fn(&mut BasicFileSystem, &[&str]) -> Result<Option<String>> + 'static
+    {
+        self.commands.push(Rc::new(Command::new(usage, Box::new(run))));
+        self
+    }
+
+    // What a handle reads before any command, generated on open
+    pub fn status<F>(mut self, status: F) -> CtlCommands
+        where F: Fn(&mut BasicFileSystem) -> String + 'static
+    {
+        self.status = Some(Rc::new(Box::new(status)));
+        self
+    }
+
+    pub fn usage(&self) -> Vec<String> {
+        self.commands.iter().map(|command| command.usage.clone()).collect()
+    }
+
+    pub fn open(&self, fs: &mut BasicFileSystem) -> RcRefBox<OpenHandler> {
+        let reply = match self.status {
+            Some(ref status) => (**status)(fs),
+            None => String::new(),
+        };
+        RcRefBox!(CtlHandler {
+            commands: self.clone(), pending: Vec::new(),
+            reply: reply.into_bytes(), errstr: String::new(),
+        })
+    }
+
+    // Run one line, giving its output or its error string
+    fn run(&self, fs: &mut BasicFileSystem, line: &str) -> result::Result<Option<String>, (Error, String)> {
+        let words = try!(tokenize(line).map_err(|msg| (Error::new(EINVAL), msg)));
+        if words.is_empty() {
+            return Ok(None);
+        }
+        let args: Vec<&str> = words[1..].iter().map(|word| &word[..]).collect();
+        let command = try!(self.commands.iter().find(|command| command.name == words[0])
+            .ok_or((Error::new(EINVAL), format!("unknown command {:?}", words[0]))));
+        if !command.accepts(args.len()) {
+            return Err((Error::new(EINVAL), format!("usage: {}", command.usage)));
+        }
+        (*command.run)(fs, &args).map_err(|err| {
+            let msg = format!("{}: {}", command.name, err);
+            (err, msg)
+        })
+    }
+}
+
+// Words of `line`, or what is wrong with its quotes
+pub fn tokenize(line: &str) -> result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            if c != '\'' {
+                word.as_mut().unwrap().push(c);
+            } else if chars.peek() == Some(&'\'') {
+                chars.next();
+                word.as_mut().unwrap().push('\'');
+            } else {
+                quoted = false;
+            }
+        } else if c == '\'' {
+            quoted = true;
+            word = Some(word.unwrap_or(String::new()));
+        } else if c.is_whitespace() {
+            if let Some(done) = word.take() {
+                words.push(done);
+            }
+        } else {
+            word = Some(word.unwrap_or(String::new()));
+            word.as_mut().unwrap().push(c);
+        }
+    }
+    if quoted {
+        return Err("unterminated quote".to_owned());
+    }
+    if let Some(done) = word {
+        words.push(done);
+    }
+    Ok(words)
+}
+
+pub struct CtlHandler {
+    commands: CtlCommands,
+    pending: Vec<u8>,   // written, not yet a complete line
+    reply: Vec<u8>,     // what reads give
+    errstr: String,     // error of the last command on this handle
+}
+
+impl CtlHandler {
+    pub fn errstr(&self) -> &str {
+        &self.errstr
+    }
+
+    // Record a failed command for reads of this handle to give
+    fn fail(&mut self, msg: String) {
+        self.reply = format!("{}\n", msg).into_bytes();
+        self.errstr = msg;
+        self.pending.clear();
+    }
+}
+
+impl OpenHandler for CtlHandler {
+    fn name(&self) -> &str {
+        &self.commands.name
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        Ok(read_at(&self.reply, offset, size))
+    }
+
+    fn write(&mut self, data: &[u8], _offset: u64, size: u64) -> Result<u64> {
+        self.pending.extend(data.iter().cloned());
+        if self.pending.split(|&b| b == b'\n').any(|line| line.len() > MAX_LINE) {
+            warn!("{}: command longer than {} bytes", self.commands.name, MAX_LINE);
+            self.fail(format!("command longer than {} bytes", MAX_LINE));
+            return Err(Error::new(E2BIG));
+        }
+        Ok(size)
+    }
+
+    // Runs the complete lines written; the first failure drops the
+    // rest of the write
+    fn commit(&mut self, fs: &mut BasicFileSystem) -> Result<()> {
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending[..end].to_vec();
+            self.pending = self.pending[end + 1..].to_vec();
+            let result = match str::from_utf8(&line) {
+                Ok(line) => self.commands.run(fs, line),
+                Err(_) => Err((Error::new(EINVAL), "command is not UTF-8".to_owned())),
+            };
+            match result {
+                Ok(output) => {
+                    self.errstr.clear();
+                    if let Some(output) = output {
+                        self.reply = output.into_bytes();
+                    }
+                },
+                Err((err, msg)) => {
+                    warn!("{}: {:?}: {}", self.commands.name, String::from_utf8_lossy(&line), msg);
+                    self.fail(msg);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Commands end with a newline; a partial last line is dropped
+    fn release(&mut self, _flags: u32, _flush: bool) -> Result<()> {
+        if !self.pending.is_empty() {
+            warn!("{}: incomplete command {:?} ignored",
+                self.commands.name, String::from_utf8_lossy(&self.pending));
+        }
+        Ok(())
+    }
+}
+
+// Ops of a ctl file, linked in by whoever installs it
+pub struct CtlOps {
+    commands: CtlCommands,
+}
+
+impl CtlOps {
+    pub fn new(commands: CtlCommands) -> RcRefBox<Operations> {
+        RcRefBox!(CtlOps { commands: commands })
+    }
+}
+
+impl Operations for CtlOps {
+    fn name(&self) -> &str {
+        "filesystem.CtlOps"
+    }
+
+    fn new_ops(&self) -> RcRefBox<Operations> {
+        Self::new(self.commands.clone())
+    }
+
+    fn rmnod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
+        Err(Error::new(EPERM))
+    }
+
+    // Recreated on install
+    fn persistent(&self) -> bool { false }
+
+    fn open(&mut self, fs: &mut BasicFileSystem, _ino: Inode, _flags: Mode)
+        -> Result<RcRefBox<OpenHandler>>
+    {
+        Ok(self.commands.open(fs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use super::libc::consts::os::posix88::*;
+    use fs::*;
+    use harness::Harness;
+    use super::{CtlCommands, CtlOps, MAX_LINE};
+
+    fn with_ctl() -> Harness {
+        let commands = CtlCommands::new("test.CtlHandler")
+            .command("echo <word>", |_, args| Ok(Some(format!("{}\n", args[0]))))
+            .status(|_| "ready\n".to_owned());
+        let mut h = Harness::new();
+        let mut attr = fileattr_new();
+        attr.ino = h.fs().alloc_inode();
+        attr.perm = 0o600;
+        let ctl = RcRef!(File::new("ctl", attr, None, CtlOps::new(commands)));
+        let root = h.fs().root();
+        h.fs().link_node(&root, Node::File(ctl)).unwrap();
+        h
+    }
+
+    // The error string stays with the handle whose command failed
+    #[test]
+    fn errstr_per_handle() {
+        let mut h = with_ctl();
+        let fh = h.open("/ctl", O_RDWR).unwrap();
+        let other = h.open("/ctl", O_RDWR).unwrap();
+        assert_eq!(h.write(fh, 0, b"nosuch\n").err(), Some(EINVAL));
+        assert_eq!(h.read(fh, 0, 100).unwrap(), b"unknown command \"nosuch\"\n".to_vec());
+        assert_eq!(h.read(other, 0, 100).unwrap(), b"ready\n".to_vec());
+        let fresh = h.open("/ctl", O_RDONLY).unwrap();
+        assert_eq!(h.read(fresh, 0, 100).unwrap(), b"ready\n".to_vec());
+        h.write(other, 0, b"echo hi\n").unwrap();
+        assert_eq!(h.read(other, 0, 100).unwrap(), b"hi\n".to_vec());
+    }
+
+    #[test]
+    fn long_line_refused() {
+        let mut h = with_ctl();
+        let fh = h.open("/ctl", O_RDWR).unwrap();
+        let long = vec![b'x'; MAX_LINE + 1];
+        assert_eq!(h.write(fh, 0, &long).err(), Some(E2BIG));
+        h.write(fh, 0, b"echo hi\n").unwrap();
+        assert_eq!(h.read(fh, 0, 100).unwrap(), b"hi\n".to_vec());
+    }
+}
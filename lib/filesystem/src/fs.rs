@@ -4,6 +4,7 @@ extern crate time;
 extern crate fuse;
 
 use std::collections::HashMap;
+use self::time::Timespec;
 use self::fuse::{FileAttr, FileType};
 use self::libc::consts::os::posix88::*; /* POSIX errno */
 
@@ -131,6 +132,17 @@ impl File {
         }
     }
 
+    // A symbolic link; its target comes from Operations::readlink()
+    pub fn symlink(name: &str, attr: FileAttr, parent: Option<Inode>,
+                   ops: RcRefBox<ops::Operations>) -> File {
+        File {
+            name: name.to_owned(),
+            attr: FileAttr { kind: FileType::Symlink, ..attr },
+            parent: parent,
+            ops: ops
+        }
+    }
+
     pub fn name(&self) -> &str { &self.name }
     pub fn attr(&self) -> &FileAttr { &self.attr }
     pub fn ops(&self) -> RcRefBox<ops::Operations> { self.ops.clone() }
@@ -143,6 +155,8 @@ pub struct Dir {
     parent: Option<Inode>,
     ops: RcRefBox<ops::Operations>,
     nodes: HashMap<String, Node>,
+    cached: HashMap<String, Timespec>,  // nodes added by the ops, until when
+    listed: Option<Timespec>,           // last readdir() of the ops holds until
 }
 
 impl Dir {
@@ -160,6 +174,8 @@ impl Dir {
             parent: parent,
             ops: ops,
             nodes: HashMap::new(),
+            cached: HashMap::new(),
+            listed: None,
         }
     }
 
@@ -173,10 +189,36 @@ impl Dir {
         self.nodes.get(name)
     }
 
+    // Keep node `name` only until `until`, see Operations::populate_ttl()
+    pub fn cache(&mut self, name: &str, until: Timespec) {
+        self.cached.insert(name.to_owned(), until);
+    }
+
+    pub fn uncache(&mut self, name: &str) {
+        self.cached.remove(name);
+    }
+
+    // Cached nodes whose time is up at `now`
+    pub fn expired(&self, now: Timespec) -> Vec<String> {
+        self.cached.iter()
+            .filter(|&(_, until)| *until <= now)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // Whether the entries the ops listed still hold at `now`
+    pub fn is_listed(&self, now: Timespec) -> bool {
+        self.listed.map_or(false, |until| now < until)
+    }
+
+    pub fn set_listed(&mut self, until: Option<Timespec>) {
+        self.listed = until;
+    }
+
     pub fn mknod(&mut self, mut node: Node) -> Result<()> {
         let name = node.name();
         if self.nodes.contains_key(&name) {
-            Err(EEXIST)
+            Err(Error::new(EEXIST))
         } else {
             node.set_parent(Some(self.attr.ino));
             self.nodes.insert(name, node);
@@ -186,14 +228,15 @@ impl Dir {
 
     pub fn rmnod(&mut self, name: &str, kind: FileType) -> Result<()> {
         let node_kind = {
-            let node = try!(self.find_node(name).ok_or(ENOENT));
+            let node = try!(self.find_node(name).ok_or(Error::new(ENOENT)));
             node.attr().kind
         };
         if node_kind == kind {
             self.nodes.remove(name);
+            self.cached.remove(name);
             Ok(())
         } else {
-            Err(ENOENT)
+            Err(Error::new(ENOENT))
         }
     }
 }
@@ -0,0 +1,375 @@
+
+extern crate libc;
+extern crate fuse;
+
+use std::result;
+use std::path::Component;
+use self::libc::c_int;
+use self::libc::consts::os::posix88::*; /* POSIX errno, open flags */
+use self::fuse::FileAttr;
+
+use fs::*;
+use common::*;
+use lock;
+use core::{BasicFileSystem, Handle, DirEntry, SetAttr};
+
+//
+// In-process driver
+//
+// Drives a BasicFileSystem through the same request handlers the FUSE
+// callbacks use, addressing nodes by path instead of inode, so that ops
+// can be exercised without /dev/fuse:
+//
+//  let mut h = Harness::new();
+//  h.fs().add_providers(netfs::PROVIDERS);
+//  try!(h.fs().load("netfs.tcp", 10, &[]));
+//  try!(h.mkdir("/net"));
+//  try!(h.mkdir("/net/tcp"));
+//  let fh = try!(h.open("/net/tcp/clone", O_RDONLY));
+//  let id = try!(h.read(fh, 0, 32));
+//
+// Every request leaves its reply in replies(), errors as the errno the
+// kernel would have been given. Requests are made for one process,
+// this one unless set_pid() says otherwise; it owns the POSIX locks
+// taken with setlk(), while flock() locks belong to the handle, as
+// with the kernel's own lock owners.
+//
+
+pub const DIR_MODE: Mode = 0o755;
+pub const FILE_MODE: Mode = 0o644;
+
+#[derive(Clone, Debug)]
+pub enum Reply {
+    Entry(FileAttr),
+    Attr(FileAttr),
+    Data(Vec<u8>),
+    Directory(Vec<DirEntry>),
+    Opened(Handle),
+    Written(u32),
+    Empty,
+    Error(c_int),
+}
+
+pub struct Harness {
+    fs: BasicFileSystem,
+    pid: u32,
+    inodes: Vec<(Handle, Inode)>,   // open handles and their nodes
+    replies: Vec<(String, Reply)>,
+}
+
+impl Harness {
+    pub fn new() -> Harness {
+        Harness::with_fs(BasicFileSystem::new())
+    }
+
+    pub fn with_fs(fs: BasicFileSystem) -> Harness {
+        let pid = unsafe { libc::getpid() } as u32;
+        Harness { fs: fs, pid: pid, inodes: Vec::new(), replies: Vec::new() }
+    }
+
+    // Act as process `pid` from now on
+    pub fn set_pid(&mut self, pid: u32) {
+        self.pid = pid;
+    }
+
+    pub fn fs(&mut self) -> &mut BasicFileSystem {
+        &mut self.fs
+    }
+
+    // Requests made so far and what they were replied
+    pub fn replies(&self) -> &[(String, Reply)] {
+        &self.replies
+    }
+
+    pub fn last_reply(&self) -> Option<&Reply> {
+        self.replies.last().map(|&(_, ref reply)| reply)
+    }
+
+    // Restores the snapshot and journal, like the kernel's init
+    pub fn mount(&mut self) -> result::Result<(), c_int> {
+        let result = self.fs.handle_init();
+        self.reply("init", result, |_| Reply::Empty)
+    }
+
+    pub fn lookup(&mut self, path: &str) -> result::Result<FileAttr, c_int> {
+        let result = self.resolve(path);
+        self.reply("lookup", result, |attr| Reply::Entry(*attr))
+    }
+
+    pub fn getattr(&mut self, path: &str) -> result::Result<FileAttr, c_int> {
+        let result = self.resolve(path)
+            .and_then(|attr| self.fs.handle_getattr(attr.ino));
+        self.reply("getattr", result, |attr| Reply::Attr(*attr))
+    }
+
+    pub fn setattr(&mut self, path: &str, set: &SetAttr) -> result::Result<FileAttr, c_int> {
+        let result = self.resolve(path)
+            .and_then(|attr| self.fs.handle_setattr(attr.ino, set));
+        self.reply("setattr", result, |attr| Reply::Attr(*attr))
+    }
+
+    pub fn readlink(&mut self, path: &str) -> result::Result<Vec<u8>, c_int> {
+        let result = self.resolve(path)
+            .and_then(|attr| self.fs.handle_readlink(attr.ino));
+        self.reply("readlink", result, |target| Reply::Data(target.clone()))
+    }
+
+    pub fn readdir(&mut self, path: &str) -> result::Result<Vec<DirEntry>, c_int> {
+        let result = self.resolve(path)
+            .and_then(|attr| self.fs.handle_readdir(attr.ino));
+        self.reply("readdir", result, |entries| Reply::Directory(entries.clone()))
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> result::Result<FileAttr, c_int> {
+        let result = self.resolve_parent(path)
+            .and_then(|(parent, name)| self.fs.handle_mkdir(parent, Path::new(&name), DIR_MODE));
+        self.reply("mkdir", result, |attr| Reply::Entry(*attr))
+    }
+
+    pub fn mknod(&mut self, path: &str) -> result::Result<FileAttr, c_int> {
+        let result = self.resolve_parent(path)
+            .and_then(|(parent, name)| self.fs.handle_mknod(parent, Path::new(&name), FILE_MODE));
+        self.reply("mknod", result, |attr| Reply::Entry(*attr))
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> result::Result<(), c_int> {
+        let result = self.resolve_parent(path)
+            .and_then(|(parent, name)| self.fs.handle_rmdir(parent, Path::new(&name)));
+        self.reply("rmdir", result, |_| Reply::Empty)
+    }
+
+    pub fn unlink(&mut self, path: &str) -> result::Result<(), c_int> {
+        let result = self.resolve_parent(path)
+            .and_then(|(parent, name)| self.fs.handle_unlink(parent, Path::new(&name)));
+        self.reply("unlink", result, |_| Reply::Empty)
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> result::Result<(), c_int> {
+        let result = self.resolve_parent(from).and_then(|(parent, name)| {
+            let (newparent, newname) = try!(self.resolve_parent(to));
+            self.fs.handle_rename(parent, Path::new(&name), newparent, Path::new(&newname))
+        });
+        self.reply("rename", result, |_| Reply::Empty)
+    }
+
+    pub fn open(&mut self, path: &str, flags: c_int) -> result::Result<Handle, c_int> {
+        let result = self.resolve(path).and_then(|attr| {
+            let fh = try!(self.fs.handle_open(attr.ino, flags as Mode, self.pid));
+            self.inodes.push((fh, attr.ino));
+            Ok(fh)
+        });
+        self.reply("open", result, |fh| Reply::Opened(*fh))
+    }
+
+    pub fn read(&mut self, fh: Handle, offset: u64, size: u32) -> result::Result<Vec<u8>, c_int> {
+        let result = self.inode(fh)
+            .and_then(|ino| self.fs.handle_read(ino, fh, offset, size));
+        self.reply("read", result, |data| Reply::Data(data.clone()))
+    }
+
+    pub fn write(&mut self, fh: Handle, offset: u64, data: &[u8]) -> result::Result<u32, c_int> {
+        let result = self.inode(fh)
+            .and_then(|ino| self.fs.handle_write(ino, fh, offset, data));
+        self.reply("write", result, |size| Reply::Written(*size))
+    }
+
+    // Never waits: a conflicting lock fails with EAGAIN
+    pub fn setlk(&mut self, fh: Handle, start: u64, end: u64, typ: u32) -> result::Result<(), c_int> {
+        let (owner, pid) = (self.pid as u64, self.pid);
+        let result = self.inode(fh)
+            .and_then(|ino| self.fs.handle_setlk(ino, owner, start, end, typ, pid, false));
+        self.reply("setlk", result, |_| Reply::Empty)
+    }
+
+    pub fn flock(&mut self, fh: Handle, typ: u32) -> result::Result<(), c_int> {
+        let pid = self.pid;
+        let result = self.inode(fh)
+            .and_then(|ino| self.fs.handle_setlk(ino, fh, 0, lock::OFFSET_MAX, typ, pid, true));
+        self.reply("flock", result, |_| Reply::Empty)
+    }
+
+    // Like close(2): a flush, then the release of the handle
+    pub fn release(&mut self, fh: Handle) -> result::Result<(), c_int> {
+        let owner = self.pid as u64;
+        let result = self.inode(fh).and_then(|ino| {
+            try!(self.fs.handle_flush(ino, fh, owner));
+            try!(self.fs.handle_release(ino, fh, 0, fh, true));
+            self.inodes.retain(|&(handle, _)| handle != fh);
+            Ok(())
+        });
+        self.reply("release", result, |_| Reply::Empty)
+    }
+
+    // Looks up every component of `path` from the root, as the kernel
+    // would before making a request on it. The kernel resolves ".."
+    // itself, so it is refused here rather than silently skipped.
+    fn resolve(&mut self, path: &str) -> Result<FileAttr> {
+        let mut attr = try!(self.fs.handle_getattr(fuse::FUSE_ROOT_ID));
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => {
+                    attr = try!(self.fs.handle_lookup(attr.ino, Path::new(name))
+                                .map_err(|err| err.with_path(path)));
+                },
+                Component::ParentDir => return Err(Error::new(EINVAL).with_path(path)),
+                _ => {},
+            }
+        }
+        Ok(attr)
+    }
+
+    fn resolve_parent(&mut self, path: &str) -> Result<(Inode, String)> {
+        let path = Path::new(path);
+        let name = try!(path.file_name().and_then(|name| name.to_str())
+                        .ok_or(Error::new(EINVAL).with_path(path)));
+        let parent = try!(self.resolve(path.parent().unwrap().to_str().unwrap()));
+        Ok((parent.ino, name.to_owned()))
+    }
+
+    fn inode(&self, fh: Handle) -> Result<Inode> {
+        self.inodes.iter().find(|&&(handle, _)| handle == fh)
+            .map(|&(_, ino)| ino).ok_or(Error::new(EBADF))
+    }
+
+    fn reply<T, F>(&mut self, op: &str, result: Result<T>, f: F) -> result::Result<T, c_int>
+        where F: Fn(&T) -> Reply
+    {
+        let (reply, result) = match result {
+            Ok(value) => {
+                let reply = f(&value);
+                (reply, Ok(value))
+            },
+            Err(err) => {
+                debug!("harness: {}: {}", op, err);
+                (Reply::Error(err.errno()), Err(err.errno()))
+            }
+        };
+        self.replies.push((op.to_owned(), reply));
+        result
+    }
+}
+
+// Helpers for the tests of each module, which drive the filesystem
+// through a Harness
+#[cfg(test)]
+pub mod fixture {
+    use std::env;
+    use std::fs as hostfs;
+    use super::Harness;
+    use super::libc;
+    use super::libc::consts::os::posix88::*;
+    use common::{Path, PathBuf};
+
+    // A scratch directory on the host, removed when dropped even if
+    // the test panics
+    pub struct TempDir {
+        path: PathBuf,
+    }
+
+    impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+            let pid = unsafe { libc::getpid() };
+            let path = env::temp_dir().join(format!("wlfs-{}-{}", name, pid));
+            let _ = hostfs::remove_dir_all(&path);
+            hostfs::create_dir_all(&path).unwrap();
+            TempDir { path: path }
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        pub fn join(&self, name: &str) -> PathBuf {
+            self.path.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = hostfs::remove_dir_all(&self.path);
+        }
+    }
+
+    pub fn names(h: &mut Harness, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = h.readdir(path).unwrap()
+            .into_iter().map(|(_, _, name)| name).collect();
+        names.sort();
+        names
+    }
+
+    pub fn contents(h: &mut Harness, path: &str) -> Vec<u8> {
+        let fh = h.open(path, O_RDONLY).unwrap();
+        let data = h.read(fh, 0, 4096).unwrap();
+        h.release(fh).unwrap();
+        data
+    }
+
+    pub fn put(h: &mut Harness, path: &str, data: &[u8]) {
+        let fh = h.open(path, O_WRONLY).unwrap();
+        h.write(fh, 0, data).unwrap();
+        h.release(fh).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Harness, Reply};
+    use super::fixture::{names, contents, put};
+    use super::libc::consts::os::posix88::*;
+
+    #[test]
+    fn mkdir_and_mknod() {
+        let mut h = Harness::new();
+        h.mkdir("/a").unwrap();
+        h.mkdir("/a/b").unwrap();
+        h.mknod("/a/f").unwrap();
+        assert_eq!(names(&mut h, "/a"), vec!["b", "f"]);
+        assert_eq!(h.mkdir("/a").err(), Some(EEXIST));
+        assert_eq!(h.lookup("/a/nosuch").err(), Some(ENOENT));
+        match h.last_reply() {
+            Some(&Reply::Error(errno)) => assert_eq!(errno, ENOENT),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut h = Harness::new();
+        h.mknod("/f").unwrap();
+        let fh = h.open("/f", O_RDWR).unwrap();
+        assert_eq!(h.write(fh, 0, b"hello world").unwrap(), 11);
+        assert_eq!(h.read(fh, 6, 32).unwrap(), b"world".to_vec());
+        h.release(fh).unwrap();
+        assert_eq!(h.getattr("/f").unwrap().size, 11);
+        assert_eq!(h.read(fh, 0, 1).err(), Some(EBADF));
+    }
+
+    #[test]
+    fn rename_moves_data() {
+        let mut h = Harness::new();
+        h.mkdir("/a").unwrap();
+        h.mkdir("/b").unwrap();
+        h.mknod("/a/f").unwrap();
+        put(&mut h, "/a/f", b"data");
+        h.rename("/a/f", "/b/g").unwrap();
+        assert_eq!(h.lookup("/a/f").err(), Some(ENOENT));
+        assert_eq!(contents(&mut h, "/b/g"), b"data".to_vec());
+    }
+
+    #[test]
+    fn rename_into_own_subtree() {
+        let mut h = Harness::new();
+        h.mkdir("/a").unwrap();
+        h.mkdir("/a/b").unwrap();
+        assert_eq!(h.rename("/a", "/a/b/c").err(), Some(EINVAL));
+        assert_eq!(names(&mut h, "/a"), vec!["b"]);
+    }
+
+    #[test]
+    fn parent_components_refused() {
+        let mut h = Harness::new();
+        h.mkdir("/a").unwrap();
+        h.mkdir("/b").unwrap();
+        assert_eq!(h.lookup("/a/../b").err(), Some(EINVAL));
+    }
+}
@@ -0,0 +1,295 @@
+
+extern crate fuse;
+
+use std::io;
+use std::io::prelude::*;
+use std::fs as hostfs;
+use self::fuse::FileAttr;
+
+use common::*;
+use persist::*;
+
+//
+// Journal format
+//
+//  record := len:u32 seq:u64 type:u8 payload
+//
+// Records are appended once a change has been applied, before it is
+// replied to, so only changes that went through are replayed. seq
+// numbers the changes across compactions: the snapshot stores the last
+// one it holds and replay skips records up to it, so a crash between
+// writing the snapshot and clearing the journal replays nothing twice.
+// A record cut short by a crash is ignored on replay, along with
+// anything after it. Nodes are named by path since inode numbers
+// are not kept across mounts.
+//
+const MKDIR: u8 = 1;
+const MKFILE: u8 = 2;
+const RMNOD: u8 = 3;
+const RENAME: u8 = 4;
+const SETATTR: u8 = 5;
+const WRITE: u8 = 6;
+
+#[derive(Debug)]
+pub enum Entry {
+    Mkdir(PathBuf, u32),
+    Mkfile(PathBuf, u32),
+    Rmnod(PathBuf, bool),               /* is_dir */
+    Rename(PathBuf, PathBuf),
+    Setattr(PathBuf, FileAttr, bool),   /* truncate to attr.size */
+    Write(PathBuf, u64, Vec<u8>),
+}
+
+fn write_path<W: Write>(w: &mut W, path: &Path) -> io::Result<()> {
+    write_str(w, path.to_str().unwrap())
+}
+
+fn read_path<R: Read>(r: &mut R) -> io::Result<PathBuf> {
+    read_str(r).map(PathBuf::from)
+}
+
+impl Entry {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            Entry::Mkdir(ref path, mode) => {
+                try!(write_u8(w, MKDIR));
+                try!(write_path(w, path));
+                write_u32(w, mode)
+            },
+            Entry::Mkfile(ref path, mode) => {
+                try!(write_u8(w, MKFILE));
+                try!(write_path(w, path));
+                write_u32(w, mode)
+            },
+            Entry::Rmnod(ref path, is_dir) => {
+                try!(write_u8(w, RMNOD));
+                try!(write_path(w, path));
+                write_u8(w, is_dir as u8)
+            },
+            Entry::Rename(ref from, ref to) => {
+                try!(write_u8(w, RENAME));
+                try!(write_path(w, from));
+                write_path(w, to)
+            },
+            Entry::Setattr(ref path, ref attr, truncate) => {
+                try!(write_u8(w, SETATTR));
+                try!(write_path(w, path));
+                try!(write_attr(w, attr));
+                write_u8(w, truncate as u8)
+            },
+            Entry::Write(ref path, offset, ref data) => {
+                try!(write_u8(w, WRITE));
+                try!(write_path(w, path));
+                try!(write_u64(w, offset));
+                write_bytes(w, data)
+            },
+        }
+    }
+
+    fn decode<R: Read>(r: &mut R) -> io::Result<Entry> {
+        match try!(read_u8(r)) {
+            MKDIR => Ok(Entry::Mkdir(try!(read_path(r)), try!(read_u32(r)))),
+            MKFILE => Ok(Entry::Mkfile(try!(read_path(r)), try!(read_u32(r)))),
+            RMNOD => Ok(Entry::Rmnod(try!(read_path(r)), try!(read_u8(r)) != 0)),
+            RENAME => Ok(Entry::Rename(try!(read_path(r)), try!(read_path(r)))),
+            SETATTR => {
+                let path = try!(read_path(r));
+                let attr = try!(read_attr(r));
+                Ok(Entry::Setattr(path, attr, try!(read_u8(r)) != 0))
+            },
+            WRITE => {
+                let path = try!(read_path(r));
+                let offset = try!(read_u64(r));
+                Ok(Entry::Write(path, offset, try!(read_bytes(r))))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::Other, "unknown journal record")),
+        }
+    }
+}
+
+pub struct Journal {
+    file: hostfs::File,
+    records: u64,
+}
+
+impl Journal {
+    // A torn or corrupt tail is cut off first, or the records appended
+    // after it would never be read back
+    pub fn open(path: &Path) -> Result<Journal> {
+        let (_, end) = try!(scan(path));
+        let file = try!(hostfs::OpenOptions::new()
+            .write(true).append(true).create(true)
+            .open(path).map_err(Error::from));
+        let len = try!(file.metadata().map_err(Error::from)).len();
+        if len > end {
+            warn!("journal: {:?} truncated from {} to {} bytes", path, len, end);
+            try!(file.set_len(end).map_err(Error::from));
+        }
+        Ok(Journal { file: file, records: 0 })
+    }
+
+    // Records appended since the journal was opened or last cleared
+    pub fn records(&self) -> u64 { self.records }
+
+    // One write(2) per record. That is enough to survive a crash of
+    // this process; it is not synced against power loss.
+    pub fn append(&mut self, seq: u64, entry: &Entry) -> Result<()> {
+        let mut payload = Vec::new();
+        // unwrap: writing to a Vec cannot fail
+        write_u64(&mut payload, seq).unwrap();
+        entry.encode(&mut payload).unwrap();
+
+        let mut record = Vec::with_capacity(payload.len() + 4);
+        write_u32(&mut record, payload.len() as u32).unwrap();
+        record.extend(payload.into_iter());
+
+        try!(self.file.write_all(&record).map_err(Error::from));
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        try!(self.file.set_len(0).map_err(Error::from));
+        self.records = 0;
+        Ok(())
+    }
+}
+
+// All complete records of the journal at `path` with their seq,
+// oldest first
+pub fn read(path: &Path) -> Result<Vec<(u64, Entry)>> {
+    scan(path).map(|(entries, _)| entries)
+}
+
+// The records read() returns and the offset where the last one ends
+fn scan(path: &Path) -> Result<(Vec<(u64, Entry)>, u64)> {
+    let file = match hostfs::File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(err) => return Err(Error::from(err)),
+    };
+    let mut r = io::BufReader::new(file);
+    let mut entries = Vec::new();
+    let mut end = 0;
+    loop {
+        let len = match read_u32(&mut r) {
+            Ok(len) => len as usize,
+            Err(_) => break,
+        };
+        // A length past the end of the file is a torn record too;
+        // the payload is taken as it is read rather than allocated
+        let mut payload = Vec::new();
+        let complete = match r.by_ref().take(len as u64).read_to_end(&mut payload) {
+            Ok(_) => payload.len() == len,
+            Err(_) => false,
+        };
+        if !complete {
+            warn!("journal: {:?} ends with a torn record, ignored", path);
+            break;
+        }
+        let mut p = &payload[..];
+        match read_u64(&mut p).and_then(|seq| Entry::decode(&mut p).map(|entry| (seq, entry))) {
+            Ok(record) => entries.push(record),
+            Err(_) => {
+                warn!("journal: {:?} has a corrupt record, replay stops there", path);
+                break;
+            }
+        }
+        end += 4 + len as u64;
+    }
+    Ok((entries, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::io::prelude::*;
+    use std::fs as hostfs;
+    use common::PathBuf;
+    use persist;
+    use core::BasicFileSystem;
+    use harness::Harness;
+    use harness::fixture::{TempDir, contents, put};
+    use ops::PassthroughOps;
+    use super::read;
+
+    fn mount(snapshot: &PathBuf, journal: &PathBuf) -> Harness {
+        let mut fs = BasicFileSystem::new();
+        fs.set_snapshot_path(Some(snapshot.clone()));
+        fs.set_journal_path(Some(journal.clone()));
+        let mut h = Harness::with_fs(fs);
+        h.mount().unwrap();
+        h
+    }
+
+    // A crash after the snapshot is written but before the journal is
+    // cleared must not replay what the snapshot holds
+    #[test]
+    fn replay_skips_snapshot() {
+        let dir = TempDir::new("journal-skip");
+        let (snapshot, journal) = (dir.join("tree"), dir.join("journal"));
+
+        let mut h = mount(&snapshot, &journal);
+        h.mknod("/a").unwrap();
+        put(&mut h, "/a", b"A");
+        h.rename("/a", "/b").unwrap();
+        h.mknod("/a").unwrap();
+        put(&mut h, "/a", b"ZZZ");
+        persist::save(h.fs(), &snapshot).unwrap();
+        mem::forget(h);
+
+        let mut h = mount(&snapshot, &journal);
+        assert_eq!(contents(&mut h, "/b"), b"A".to_vec());
+        assert_eq!(contents(&mut h, "/a"), b"ZZZ".to_vec());
+    }
+
+    // Changes made after a torn record must survive the next mount,
+    // also with no snapshot to compact into
+    #[test]
+    fn torn_tail_cut_on_open() {
+        let dir = TempDir::new("journal-torn");
+        let journal = dir.join("journal");
+        let mount = || {
+            let mut fs = BasicFileSystem::new();
+            fs.set_journal_path(Some(journal.clone()));
+            let mut h = Harness::with_fs(fs);
+            h.mount().unwrap();
+            h
+        };
+
+        let mut h = mount();
+        h.mknod("/a").unwrap();
+        mem::forget(h);
+        let mut file = hostfs::OpenOptions::new().append(true).open(&journal).unwrap();
+        file.write_all(&[0, 0, 0, 64, 1, 2]).unwrap();
+        drop(file);
+
+        let mut h = mount();
+        h.mknod("/b").unwrap();
+        put(&mut h, "/b", b"B");
+        mem::forget(h);
+
+        let mut h = mount();
+        assert!(h.lookup("/a").is_ok());
+        assert_eq!(contents(&mut h, "/b"), b"B".to_vec());
+    }
+
+    // Host files are the state of a passthrough mount; replaying their
+    // creation would create them on the host again
+    #[test]
+    fn passthrough_not_journaled() {
+        let dir = TempDir::new("journal-passthrough");
+        let host = TempDir::new("journal-passthrough-host");
+        let journal = dir.join("journal");
+        let mut fs = BasicFileSystem::new();
+        fs.set_journal_path(Some(journal.clone()));
+        let mut h = Harness::with_fs(fs);
+        h.mount().unwrap();
+        h.fs().register_ops(10, PassthroughOps::new("/p", host.path().to_str().unwrap()));
+        h.mknod("/p/f").unwrap();
+        put(&mut h, "/p/f", b"data");
+        h.mkdir("/p/d").unwrap();
+        h.rename("/p/f", "/p/d/f").unwrap();
+        assert_eq!(read(&journal).unwrap().len(), 0);
+    }
+}
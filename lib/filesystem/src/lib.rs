@@ -2,6 +2,7 @@
 #![feature(libc)]
 #![feature(core)]
 #![feature(collections)]
+#![feature(rc_counts)]
 #![allow(dead_code)]
 #![allow(non_camel_case_types)]
 
@@ -14,3 +15,14 @@ pub mod common;
 pub mod fs;
 pub mod ops;
 pub mod core;
+pub mod lock;
+pub mod chunk;
+pub mod persist;
+pub mod journal;
+pub mod archive;
+pub mod snapshot;
+pub mod control;
+pub mod router;
+pub mod synthetic;
+pub mod ctl;
+pub mod harness;
@@ -0,0 +1,198 @@
+
+extern crate libc;
+
+use std::mem;
+use std::collections::HashMap;
+use self::libc::consts::os::posix88::*; /* POSIX errno */
+
+use fs::*;
+use common::*;
+
+pub type LockOwner = u64;
+pub type LockType = u32;
+
+/* fcntl(2) lock types on Linux */
+pub const F_RDLCK: LockType = 0;
+pub const F_WRLCK: LockType = 1;
+pub const F_UNLCK: LockType = 2;
+
+/* Largest offset the kernel passes for "until end of file" */
+pub const OFFSET_MAX: u64 = 0x7fffffffffffffff;
+
+// A byte range [start, end] (inclusive) held by one owner.
+// Whole-file (flock) locks are ranges over [0, OFFSET_MAX]. The two
+// kinds conflict with each other, as when FUSE emulates flock(2) with
+// POSIX locks, but are dropped at different times: POSIX locks when
+// their owner flushes the file, flock locks when it is released.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lock {
+    pub owner: LockOwner,
+    pub start: u64,
+    pub end: u64,
+    pub typ: LockType,
+    pub pid: u32,
+    pub flock: bool,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner &&
+            self.overlaps(other.start, other.end) &&
+            (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+#[derive(Debug)]
+pub struct LockManager {
+    locks: HashMap<Inode, Vec<Lock>>,
+}
+
+impl LockManager {
+    pub fn new() -> LockManager {
+        LockManager { locks: HashMap::new() }
+    }
+
+    pub fn locks(&self, ino: Inode) -> &[Lock] {
+        match self.locks.get(&ino) {
+            Some(locks) => &locks[..],
+            None => &[],
+        }
+    }
+
+    pub fn is_locked(&self, ino: Inode) -> bool {
+        !self.locks(ino).is_empty()
+    }
+
+    // The lock if the whole file is write-locked by a single owner
+    pub fn exclusive_lock(&self, ino: Inode) -> Option<Lock> {
+        self.locks(ino).iter()
+            .find(|l| l.typ == F_WRLCK && l.start == 0 && l.end >= OFFSET_MAX)
+            .cloned()
+    }
+
+    // First lock that would prevent `owner` from taking `typ` on the range
+    pub fn getlk(&self, ino: Inode, owner: LockOwner, start: u64, end: u64, typ: LockType)
+        -> Option<Lock>
+    {
+        let wanted = Lock { owner: owner, start: start, end: end, typ: typ, pid: 0, flock: false };
+        self.locks(ino).iter().find(|l| l.conflicts(&wanted)).cloned()
+    }
+
+    // Take, change or drop (F_UNLCK) `lock`, among the owner's locks of
+    // the same kind. Never blocks: a conflicting request fails with
+    // EAGAIN, and it is up to the caller to try again once locks go away
+    // (see setlk() of BasicFileSystem), since waiting here would stall the
+    // single-threaded FUSE loop that has to deliver the unlock.
+    pub fn set(&mut self, ino: Inode, lock: Lock) -> Result<()> {
+        if lock.start > lock.end {
+            return Err(Error::new(EINVAL));
+        }
+        match lock.typ {
+            F_UNLCK => {
+                self.punch(ino, lock.owner, lock.start, lock.end, lock.flock);
+                Ok(())
+            },
+            F_RDLCK | F_WRLCK => {
+                if self.getlk(ino, lock.owner, lock.start, lock.end, lock.typ).is_some() {
+                    return Err(Error::new(EAGAIN));
+                }
+                self.punch(ino, lock.owner, lock.start, lock.end, lock.flock);
+                self.locks.entry(ino).or_insert(Vec::new()).push(lock);
+                Ok(())
+            },
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    // A POSIX (fcntl) lock
+    pub fn setlk(&mut self, ino: Inode, owner: LockOwner, start: u64, end: u64,
+                 typ: LockType, pid: u32) -> Result<()> {
+        self.set(ino, Lock { owner: owner, start: start, end: end, typ: typ, pid: pid, flock: false })
+    }
+
+    pub fn flock(&mut self, ino: Inode, owner: LockOwner, typ: LockType, pid: u32) -> Result<()> {
+        self.set(ino, Lock { owner: owner, start: 0, end: OFFSET_MAX, typ: typ, pid: pid, flock: true })
+    }
+
+    // Drop the POSIX locks `owner` holds on `ino`, on close(2) of any
+    // of its descriptors for the file
+    pub fn flush(&mut self, ino: Inode, owner: LockOwner) {
+        self.punch(ino, owner, 0, OFFSET_MAX, false);
+    }
+
+    // Drop every lock `owner` holds on `ino`
+    pub fn release(&mut self, ino: Inode, owner: LockOwner) {
+        self.punch(ino, owner, 0, OFFSET_MAX, false);
+        self.punch(ino, owner, 0, OFFSET_MAX, true);
+    }
+
+    // Drop every lock on `ino`, e.g. when the node goes away
+    pub fn forget(&mut self, ino: Inode) {
+        self.locks.remove(&ino);
+    }
+
+    // Remove [start, end] from the ranges of the kind `flock` held by
+    // `owner`, splitting locks that straddle the boundaries.
+    fn punch(&mut self, ino: Inode, owner: LockOwner, start: u64, end: u64, flock: bool) {
+        let empty = match self.locks.get_mut(&ino) {
+            Some(locks) => {
+                let old = mem::replace(locks, Vec::new());
+                for l in old.into_iter() {
+                    if l.owner != owner || l.flock != flock || !l.overlaps(start, end) {
+                        locks.push(l);
+                        continue;
+                    }
+                    if l.start < start {
+                        locks.push(Lock { end: start - 1, ..l });
+                    }
+                    if l.end > end {
+                        locks.push(Lock { start: end + 1, ..l });
+                    }
+                }
+                locks.is_empty()
+            },
+            None => false,
+        };
+        if empty {
+            self.locks.remove(&ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::libc;
+    use super::libc::consts::os::posix88::*;
+    use super::{F_RDLCK, F_WRLCK, F_UNLCK};
+    use harness::Harness;
+
+    // Closing any descriptor drops the POSIX locks of the process;
+    // flock locks go with the handle they were taken on
+    #[test]
+    fn dropped_on_flush_and_release() {
+        let mut h = Harness::new();
+        h.mknod("/f").unwrap();
+        let fh = h.open("/f", O_RDWR).unwrap();
+        let other = h.open("/f", O_RDONLY).unwrap();
+        h.setlk(fh, 0, 9, F_WRLCK).unwrap();
+        h.flock(other, F_RDLCK).unwrap();
+
+        h.set_pid(1);
+        let theirs = h.open("/f", O_RDWR).unwrap();
+        assert_eq!(h.setlk(theirs, 5, 5, F_RDLCK).err(), Some(EAGAIN));
+        assert_eq!(h.flock(theirs, F_WRLCK).err(), Some(EAGAIN));
+
+        h.set_pid(unsafe { libc::getpid() } as u32);
+        h.release(fh).unwrap();
+        h.set_pid(1);
+        h.setlk(theirs, 5, 5, F_RDLCK).unwrap();
+        h.setlk(theirs, 5, 5, F_UNLCK).unwrap();
+        assert_eq!(h.flock(theirs, F_WRLCK).err(), Some(EAGAIN));
+        h.release(other).unwrap();
+        h.flock(theirs, F_WRLCK).unwrap();
+    }
+}
@@ -1,36 +1,125 @@
 
 extern crate libc;
+extern crate time;
 extern crate fuse;
 
-use std::slice;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::fs as hostfs;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::path::Component;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use self::time::{Timespec, Duration};
 use self::fuse::{FileType, FileAttr};
-use self::libc::consts::os::posix88::*; /* POSIX errno */
+use self::libc::{c_char, c_int, c_long};
+use self::libc::consts::os::posix88::*; /* POSIX errno, open flags */
 
 use fs::*;
 use common::*;
-use core::BasicFileSystem;
+use chunk::{ChunkStore, CHUNK_SIZE};
+use archive;
+use archive::Archive;
+use persist;
+use router::Route;
+use core::{BasicFileSystem, SetAttr, Usage, get_path};
 
 pub trait Operations {
     fn name(&self) -> &str;
     fn new_ops(&self) -> RcRefBox<Operations>;
     fn install(&mut self, _fs: &mut BasicFileSystem) -> bool { true }
     fn uninstall(&mut self, _fs: &mut BasicFileSystem) -> bool { true }
+    // Paths served, as rules for the router. Ops without routes are asked
+    // with is_target() instead.
+    fn routes(&self) -> Vec<Route> { Vec::new() }
     fn is_target(&mut self, _path: &Path, _kind: FileType) -> bool { false }
-    fn getattr(&mut self, node: Node) -> Result<FileAttr> {
+    fn getattr(&mut self, _fs: &mut BasicFileSystem, node: Node) -> Result<FileAttr> {
         Ok(node.attr())
     }
+    // Called with the fields asked for, before the tree is updated; an
+    // error leaves the node as is
+    fn setattr(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _set: &SetAttr) -> Result<()> {
+        Ok(())
+    }
+    fn rename(&mut self, _fs: &mut BasicFileSystem, _ino: Inode,
+              _newparent: Inode, _newname: &str) -> Result<()> {
+        Ok(())
+    }
+    // Directory hooks, called on the directory's ops. lookup() runs when
+    // `name` is not in the tree and may add it; readdir() runs before the
+    // directory is listed and may bring its entries up to date.
+    fn lookup(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _name: &str)
+        -> Result<Option<Node>>
+    {
+        Ok(None)
+    }
+    fn readdir(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
+        Ok(())
+    }
+    // With a TTL, the nodes lookup() and readdir() add are dropped from
+    // the tree once it runs out and asked for again when next needed,
+    // and readdir() only runs again when its last listing is that old;
+    // nodes linked again under their old name get their old inode back.
+    // None keeps them and runs readdir() on every listing.
+    fn populate_ttl(&self) -> Option<Duration> { None }
+    // How long the kernel may cache the name and the attributes of the
+    // node without asking again. Zero, the default, for nodes that can
+    // change behind its back, such as live sockets or the files a
+    // rollback rewrites.
+    fn entry_ttl(&self) -> Timespec { Timespec::new(0, 0) }
+    fn attr_ttl(&self) -> Timespec { Timespec::new(0, 0) }
     fn mknod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _perm: Perm) -> Result<()> {
         Ok(())
     }
     fn rmnod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
         Ok(())
     }
-    fn open(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _perm: Perm)
+    fn truncate(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _size: u64) -> Result<()> {
+        Ok(())
+    }
+    // Target of a symbolic link node
+    fn readlink(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<Vec<u8>> {
+        Err(Error::new(EINVAL))
+    }
+    // Whether the node and its subtree are kept across mounts. Ops bound
+    // to live resources or to host state say no: they are left out of
+    // snapshots, and their changes out of the journal so that a mount
+    // does not replay them.
+    fn persistent(&self) -> bool { true }
+    // State saved along with the node in a snapshot, asked of persistent()
+    // ops only. None leaves the node and its subtree out.
+    fn snapshot(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+    // Called instead of mknod() when a node is rebuilt from a snapshot.
+    // An error drops the node.
+    fn restore(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+    // Copy of the node's data for a snapshot (see the snapshot module),
+    // sharing chunks with the live node. None leaves the node and its
+    // subtree out; directories return an empty store.
+    fn freeze(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Option<ChunkStore> {
+        None
+    }
+    // Counterpart of freeze(): take `data` back on rollback
+    fn thaw(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _data: ChunkStore) -> Result<()> {
+        Err(Error::new(EINVAL))
+    }
+    // Whether writes are journaled and replayed on mount. Only for ops
+    // whose data lives in the tree: replaying a socket write resends it.
+    fn journal_writes(&self) -> bool { false }
+    // Whether the node can still be opened for writing, written and have
+    // its attributes set on a read-only filesystem, e.g. a query file
+    fn writable_when_read_only(&self) -> bool { false }
+    fn open(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _flags: Mode)
         -> Result<RcRefBox<OpenHandler>>
     {
-        Err(ENOSYS)
+        Err(Error::new(ENOSYS))
     }
 }
 
@@ -42,6 +131,11 @@ impl fmt::Debug for Operations {
     }
 }
 
+/* Cache lifetime of nodes that never change once built. They can still
+   go away as a whole (unload, a dropped snapshot) and the kernel cannot
+   be told, so it is kept to libfuse's default of one second. */
+pub const STATIC_TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
 pub trait OpenHandler {
     fn name(&self) -> &str;
     fn read(&mut self, _offset: u64, _size: u64) -> Result<Vec<u8>>;
@@ -49,18 +143,32 @@ pub trait OpenHandler {
     fn release (&mut self, _flags: u32, _flush: bool) -> Result<()> {
         Ok(())
     }
+    // Whether reads and writes bypass the page cache. Stream-like handles
+    // need it: their data has no stable offsets, and generated files
+    // report no size. Handles over stored data turn it off, which also
+    // makes mmap work.
+    fn direct_io(&self) -> bool { true }
+    // Without direct I/O, whether the cached pages of the file are still
+    // good on open, i.e. its data only changes through the mount
+    fn keep_cache(&self) -> bool { false }
+    // Runs after each successful write, with access to the tree.
+    // Control files queue commands in write() and carry them out here;
+    // an error fails the write.
+    fn commit(&mut self, _fs: &mut BasicFileSystem) -> Result<()> {
+        Ok(())
+    }
 }
 
 //
 // File Operations
 //
 pub struct FileOps {
-    data: RcRef<Vec<u8>>
+    data: RcRef<ChunkStore>
 }
 
 impl FileOps {
     pub fn new() -> RcRefBox<Operations> {
-        RcRefBox!(FileOps { data: RcRef!(Vec::new()) })
+        RcRefBox!(FileOps { data: RcRef!(ChunkStore::new()) })
     }
 }
 
@@ -73,13 +181,17 @@ impl Operations for FileOps {
         Self::new()
     }
 
-    fn is_target(&mut self, _path: &Path, kind: FileType) -> bool {
-        kind == FileType::RegularFile
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::glob("/**").kind(FileType::RegularFile)]
     }
 
-    fn getattr(&mut self, node: Node) -> Result<FileAttr> {
+    fn journal_writes(&self) -> bool { true }
+
+    fn getattr(&mut self, _fs: &mut BasicFileSystem, node: Node) -> Result<FileAttr> {
+        let data = self.data.borrow();
         Ok(FileAttr {
-            size: self.data.borrow().len() as u64,
+            size: data.len(),
+            blocks: data.blocks(),
             ..node.attr()
         })
     }
@@ -88,26 +200,64 @@ impl Operations for FileOps {
         Ok(())
     }
 
-    fn rmnod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
+    // Chunks a snapshot shares stay charged (see Usage)
+    fn rmnod(&mut self, fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
+        fs.usage().borrow_mut().release(self.data.borrow().unshared());
         Ok(())
     }
 
-    fn open(&mut self, _fs: &mut BasicFileSystem, ino: Inode, perm: Perm)
+    fn truncate(&mut self, fs: &mut BasicFileSystem, _ino: Inode, size: u64) -> Result<()> {
+        let usage = fs.usage();
+        try!(usage.borrow_mut().reserve(self.data.borrow().allocation_for_truncate(size)));
+        let released = self.data.borrow_mut().truncate(size);
+        usage.borrow_mut().release(released);
+        Ok(())
+    }
+
+    fn snapshot(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        // unwrap: writing to a Vec cannot fail
+        self.data.borrow().save(&mut buf).unwrap();
+        Some(buf)
+    }
+
+    fn restore(&mut self, fs: &mut BasicFileSystem, ino: Inode, data: &[u8]) -> Result<()> {
+        let store = try!(ChunkStore::load(&mut &data[..]).or(Err(Error::new(EIO))));
+        self.thaw(fs, ino, store)
+    }
+
+    fn freeze(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Option<ChunkStore> {
+        Some(self.data.borrow().clone())
+    }
+
+    // Chunks `data` shares with a snapshot are charged already
+    fn thaw(&mut self, fs: &mut BasicFileSystem, _ino: Inode, data: ChunkStore) -> Result<()> {
+        let usage = fs.usage();
+        try!(usage.borrow_mut().reserve(data.unshared()));
+        usage.borrow_mut().release(self.data.borrow().unshared());
+        *self.data.borrow_mut() = data;
+        Ok(())
+    }
+
+    fn open(&mut self, fs: &mut BasicFileSystem, ino: Inode, flags: Mode)
         -> Result<RcRefBox<OpenHandler>>
     {
-        Ok(FileHandler::open(ino, perm, self.data.clone()))
+        Ok(FileHandler::open(ino, flags, self.data.clone(), fs.usage()))
     }
 }
 
 struct FileHandler {
     ino: Inode,
-    perm: Perm,
-    data: RcRef<Vec<u8>>
+    flags: Mode,
+    data: RcRef<ChunkStore>,
+    usage: RcRef<Usage>,
 }
 
 impl FileHandler {
-    fn open(ino: Inode, perm: Perm, data: RcRef<Vec<u8>>) -> RcRefBox<OpenHandler> {
-        RcRefBox!(FileHandler { ino: ino, perm: perm, data: data })
+    fn open(ino: Inode, flags: Mode, data: RcRef<ChunkStore>, usage: RcRef<Usage>)
+        -> RcRefBox<OpenHandler>
+    {
+        RcRefBox!(FileHandler { ino: ino, flags: flags, data: data, usage: usage })
     }
 }
 
@@ -116,29 +266,18 @@ impl OpenHandler for FileHandler {
         "FileOps.FileHandler"
     }
 
-    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
-        let data = self.data.borrow();
-        let len = data.len() as u64;
+    // A rollback rewrites the data behind the kernel's back and it cannot
+    // be told to drop its pages, so they are not kept across opens
+    fn direct_io(&self) -> bool { false }
 
-        Ok(if offset > len {
-            Vec::new()
-        } else {
-            let begin = offset as usize;
-            let end = if offset + size < len { offset + size } else { len } as usize;
-            (&data[begin .. end]).to_owned()
-        })
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        Ok(self.data.borrow().read(offset, size))
     }
 
     fn write(&mut self, src: &[u8], offset: u64, size: u64) -> Result<u64> {
-        let begin = offset as usize;
-        let end = (offset + size) as usize;
         let mut dst = self.data.borrow_mut();
-
-        if end > dst.len() {
-            dst.resize(end, 0);
-        }
-
-        slice::bytes::copy_memory(src, &mut dst[begin..end]);
+        try!(self.usage.borrow_mut().reserve(dst.allocation_for(offset, src)));
+        dst.write(offset, src);
         Ok(size)
     }
 }
@@ -161,8 +300,8 @@ impl Operations for DirOps {
         Self::new()
     }
 
-    fn is_target(&mut self, _path: &Path, kind: FileType) -> bool {
-        kind == FileType::Directory
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::glob("/**").kind(FileType::Directory)]
     }
 
     fn mknod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _perm: Perm) -> Result<()> {
@@ -172,4 +311,1079 @@ impl Operations for DirOps {
     fn rmnod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
         Ok(())
     }
+
+    fn freeze(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Option<ChunkStore> {
+        Some(ChunkStore::new())
+    }
+
+    fn thaw(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _data: ChunkStore) -> Result<()> {
+        Ok(())
+    }
+}
+
+//
+// Passthrough Operations
+//
+// Mirrors a host directory under `prefix`. Nodes are added to the tree
+// as they are looked up or listed; every change goes to the host first.
+// Host symlinks are neither listed nor followed, so nothing outside the
+// host directory can be reached.
+//
+pub struct PassthroughOps {
+    prefix: PathBuf,
+    host: PathBuf,
+}
+
+impl PassthroughOps {
+    pub fn new(prefix: &str, host: &str) -> RcRefBox<Operations> {
+        RcRefBox!(PassthroughOps { prefix: PathBuf::from(prefix), host: PathBuf::from(host) })
+    }
+
+    fn host_path_of(&self, path: &Path) -> PathBuf {
+        self.host.join(relative_to(path, &self.prefix))
+    }
+
+    // ELOOP if a component turned into a symlink since it was looked up
+    fn host_path(&self, fs: &BasicFileSystem, ino: Inode) -> Result<PathBuf> {
+        let node = try!(fs.find_node(ino).ok_or(Error::new(ENOENT)));
+        let path = self.host_path_of(&get_path(fs, node));
+        let mut ancestor = Some(path.as_path());
+        while let Some(dir) = ancestor {
+            if dir == self.host.as_path() {
+                return Ok(path.clone());
+            }
+            if let Ok(meta) = hostfs::symlink_metadata(dir) {
+                if meta.file_type().is_symlink() {
+                    return Err(Error::new(ELOOP).with_path(dir));
+                }
+            }
+            ancestor = dir.parent();
+        }
+        Ok(path.clone())
+    }
+
+    // Add a host entry to the tree, or refresh the attributes of the
+    // node already standing for it
+    fn link_host_entry(&self, fs: &mut BasicFileSystem, parent_dir: &RcRef<Dir>, name: &str,
+                       meta: &hostfs::Metadata) -> Result<Node> {
+        let existing = parent_dir.borrow().find_node(name).cloned();
+        if let Some(mut node) = existing {
+            let attr = host_attr(meta, node.attr().ino);
+            node.set_attr(attr);
+            return Ok(node);
+        }
+
+        let attr = host_attr(meta, fs.alloc_inode());
+        let node = if meta.is_dir() {
+            Node::Dir(RcRef!(Dir::new(name, attr, None, self.new_ops())))
+        } else {
+            Node::File(RcRef!(File::new(name, attr, None, self.new_ops())))
+        };
+        try!(fs.link_node(parent_dir, node.clone()));
+        Ok(node)
+    }
+}
+
+// `path` below `prefix`, e.g. /data/a/b below /data is a/b
+fn relative_to(path: &Path, prefix: &Path) -> PathBuf {
+    let mut rel = PathBuf::new();
+    for component in path.components().skip(prefix.components().count()) {
+        if let Component::Normal(name) = component {
+            rel.push(name);
+        }
+    }
+    rel
+}
+
+// Link a directory for `prefix` bound to `ops`, creating its parents
+fn bind_prefix(fs: &mut BasicFileSystem, prefix: &Path, attr: FileAttr,
+               ops: RcRefBox<Operations>) -> bool {
+    let (parent, name) = match (prefix.parent(), prefix.file_name()) {
+        (Some(parent), Some(name)) => (parent.to_owned(), name.to_str().unwrap().to_owned()),
+        _ => return false,
+    };
+    let parent_dir = match fs.mkdir_all(&parent, 0o755) {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    if parent_dir.borrow().find_node(&name).is_some() {
+        error!("install: {:?} already exists", prefix);
+        return false;
+    }
+    let attr = FileAttr { ino: fs.alloc_inode(), ..attr };
+    fs.link_node(&parent_dir, Node::Dir(RcRef!(Dir::new(&name, attr, None, ops)))).is_ok()
+}
+
+fn unbind_prefix(fs: &mut BasicFileSystem, prefix: &Path) {
+    let node = fs.lookup_path(prefix);
+    let parent = prefix.parent().and_then(|p| fs.lookup_path(p));
+    if let (Some(node), Some(parent)) = (node, parent) {
+        fs.unlink_node(parent.to_dir(), &node);
+    }
+}
+
+fn host_attr(meta: &hostfs::Metadata, ino: Inode) -> FileAttr {
+    FileAttr {
+        ino: ino,
+        size: meta.size() as u64,
+        blocks: meta.blocks() as u64,
+        atime: Timespec::new(meta.atime() as i64, meta.atime_nsec() as i32),
+        mtime: Timespec::new(meta.mtime() as i64, meta.mtime_nsec() as i32),
+        ctime: Timespec::new(meta.ctime() as i64, meta.ctime_nsec() as i32),
+        crtime: Timespec::new(meta.ctime() as i64, meta.ctime_nsec() as i32),
+        kind: if meta.is_dir() { FileType::Directory } else { FileType::RegularFile },
+        perm: (meta.mode() & 0o7777) as Perm,
+        nlink: meta.nlink() as u32,
+        uid: meta.uid() as u32,
+        gid: meta.gid() as u32,
+        rdev: meta.rdev() as u32,
+        flags: 0,
+    }
+}
+
+fn host_cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).or(Err(Error::new(EINVAL)))
+}
+
+/* Access mode bits of open(2) flags */
+pub const ACCMODE: c_int = 0o3;
+
+#[repr(C)]
+struct timespec {
+    tv_sec: c_long,
+    tv_nsec: c_long,
+}
+
+/* utimensat(2) on Linux */
+const AT_FDCWD: c_int = -100;
+const AT_SYMLINK_NOFOLLOW: c_int = 0x100;
+const UTIME_OMIT: c_long = (1 << 30) - 2;
+
+extern {
+    fn chown(path: *const c_char, owner: u32, group: u32) -> c_int;
+    fn utimensat(dirfd: c_int, path: *const c_char, times: *const timespec, flags: c_int) -> c_int;
+}
+
+fn last_error() -> Error {
+    Error::from(io::Error::last_os_error())
+}
+
+impl Operations for PassthroughOps {
+    fn name(&self) -> &str {
+        "filesystem.PassthroughOps"
+    }
+
+    fn new_ops(&self) -> RcRefBox<Operations> {
+        RcRefBox!(PassthroughOps { prefix: self.prefix.clone(), host: self.host.clone() })
+    }
+
+    // Bind the host directory at `prefix`, creating the parents as needed
+    fn install(&mut self, fs: &mut BasicFileSystem) -> bool {
+        let meta = match hostfs::metadata(&self.host) {
+            Ok(ref meta) if meta.is_dir() => meta.clone(),
+            _ => {
+                error!("install: {:?} is not a host directory", self.host);
+                return false;
+            }
+        };
+        bind_prefix(fs, &self.prefix, host_attr(&meta, 0), self.new_ops())
+    }
+
+    fn uninstall(&mut self, fs: &mut BasicFileSystem) -> bool {
+        unbind_prefix(fs, &self.prefix);
+        true
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::subtree(&self.prefix)]
+    }
+
+    fn getattr(&mut self, fs: &mut BasicFileSystem, mut node: Node) -> Result<FileAttr> {
+        let path = try!(self.host_path(fs, node.attr().ino));
+        let meta = try!(hostfs::symlink_metadata(&path).map_err(Error::from));
+        let attr = host_attr(&meta, node.attr().ino);
+        node.set_attr(attr);
+        Ok(attr)
+    }
+
+    // Only the fields asked for are changed, the host file may have
+    // changed since the tree last saw it. A size change comes through
+    // truncate(), and ownership changes need privileges.
+    fn setattr(&mut self, fs: &mut BasicFileSystem, ino: Inode, set: &SetAttr) -> Result<()> {
+        let path = try!(self.host_path(fs, ino));
+        let cpath = try!(host_cstring(&path));
+
+        if let Some(mode) = set.mode {
+            try!(hostfs::set_permissions(&path, hostfs::Permissions::from_mode(mode & 0o7777))
+                .map_err(Error::from));
+        }
+        if set.atime.is_some() || set.mtime.is_some() {
+            let time = |time: Option<Timespec>| match time {
+                Some(time) => timespec { tv_sec: time.sec as c_long, tv_nsec: time.nsec as c_long },
+                None => timespec { tv_sec: 0, tv_nsec: UTIME_OMIT },
+            };
+            let times = [time(set.atime), time(set.mtime)];
+            if unsafe { utimensat(AT_FDCWD, cpath.as_ptr(), times.as_ptr(), AT_SYMLINK_NOFOLLOW) } != 0 {
+                return Err(last_error());
+            }
+        }
+        if set.uid.is_some() || set.gid.is_some() {
+            // -1 leaves that id as it is
+            let (uid, gid) = (set.uid.unwrap_or(!0), set.gid.unwrap_or(!0));
+            if unsafe { chown(cpath.as_ptr(), uid, gid) } != 0 {
+                return Err(last_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, fs: &mut BasicFileSystem, ino: Inode, size: u64) -> Result<()> {
+        let path = try!(self.host_path(fs, ino));
+        let file = try!(hostfs::OpenOptions::new().write(true).open(&path).map_err(Error::from));
+        file.set_len(size).map_err(Error::from)
+    }
+
+    fn rename(&mut self, fs: &mut BasicFileSystem, ino: Inode,
+              newparent: Inode, newname: &str) -> Result<()> {
+        let target = {
+            let parent = try!(fs.find_node(newparent).ok_or(Error::new(ENOENT)));
+            get_path(fs, parent).join(newname)
+        };
+        if !target.starts_with(&self.prefix) {
+            return Err(Error::new(EXDEV));
+        }
+        let from = try!(self.host_path(fs, ino));
+        hostfs::rename(&from, &self.host_path_of(&target)).map_err(Error::from)
+    }
+
+    fn lookup(&mut self, fs: &mut BasicFileSystem, ino: Inode, name: &str) -> Result<Option<Node>> {
+        let path = try!(self.host_path(fs, ino)).join(name);
+        let meta = match hostfs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::from(err)),
+        };
+        if !meta.is_dir() && !meta.is_file() {
+            return Ok(None);
+        }
+        let parent_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).to_dir().clone();
+        self.link_host_entry(fs, &parent_dir, name, &meta).map(Some)
+    }
+
+    // Resync the directory with the host. Nodes of other ops that were
+    // placed here are left alone.
+    fn readdir(&mut self, fs: &mut BasicFileSystem, ino: Inode) -> Result<()> {
+        let path = try!(self.host_path(fs, ino));
+        let parent_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).to_dir().clone();
+
+        let mut seen = Vec::new();
+        for entry in try!(hostfs::read_dir(&path).map_err(Error::from)) {
+            let entry = try!(entry.map_err(Error::from));
+            let name = match entry.file_name().to_str() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let meta = match hostfs::symlink_metadata(&entry.path()) {
+                Ok(ref meta) if meta.is_dir() || meta.is_file() => meta.clone(),
+                _ => continue,
+            };
+            try!(self.link_host_entry(fs, &parent_dir, &name, &meta));
+            seen.push(name);
+        }
+
+        let stale: Vec<Node> = parent_dir.borrow().nodes().iter()
+            .filter(|&(name, node)| !seen.contains(name) && node.ops().borrow().name() == self.name())
+            .map(|(_, node)| node.clone())
+            .collect();
+        for node in stale.iter() {
+            fs.unlink_node(&parent_dir, node);
+        }
+        Ok(())
+    }
+
+    fn mknod(&mut self, fs: &mut BasicFileSystem, ino: Inode, perm: Perm) -> Result<()> {
+        let path = try!(self.host_path(fs, ino));
+        let is_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).is_dir();
+        if is_dir {
+            try!(hostfs::create_dir(&path).map_err(Error::from));
+        } else {
+            try!(hostfs::OpenOptions::new().write(true).create(true).open(&path).map_err(Error::from));
+        }
+        hostfs::set_permissions(&path, hostfs::Permissions::from_mode(perm as u32)).map_err(Error::from)
+    }
+
+    fn rmnod(&mut self, fs: &mut BasicFileSystem, ino: Inode) -> Result<()> {
+        let path = try!(self.host_path(fs, ino));
+        let is_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).is_dir();
+        if is_dir {
+            hostfs::remove_dir(&path).map_err(Error::from)
+        } else {
+            hostfs::remove_file(&path).map_err(Error::from)
+        }
+    }
+
+    // The host directory is the state; nothing to keep or replay
+    fn persistent(&self) -> bool { false }
+
+    fn open(&mut self, fs: &mut BasicFileSystem, ino: Inode, flags: Mode)
+        -> Result<RcRefBox<OpenHandler>>
+    {
+        let path = try!(self.host_path(fs, ino));
+        let flags = flags as c_int;
+        let file = try!(hostfs::OpenOptions::new()
+            .read(flags & ACCMODE != O_WRONLY)
+            .write(flags & ACCMODE != O_RDONLY)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .open(&path).map_err(Error::from));
+        Ok(RcRefBox!(PassthroughHandler { file: file }))
+    }
+}
+
+struct PassthroughHandler {
+    file: hostfs::File,
+}
+
+impl OpenHandler for PassthroughHandler {
+    fn name(&self) -> &str {
+        "PassthroughOps.PassthroughHandler"
+    }
+
+    // The host file may change under the mount, so no keep_cache
+    fn direct_io(&self) -> bool { false }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        try!(self.file.seek(SeekFrom::Start(offset)).map_err(Error::from));
+        let mut buf = Vec::new();
+        try!((&mut self.file).take(size).read_to_end(&mut buf).map_err(Error::from));
+        Ok(buf)
+    }
+
+    fn write(&mut self, src: &[u8], offset: u64, size: u64) -> Result<u64> {
+        try!(self.file.seek(SeekFrom::Start(offset)).map_err(Error::from));
+        try!(self.file.write_all(src).map_err(Error::from));
+        Ok(size)
+    }
+
+    fn release(&mut self, _flags: u32, flush: bool) -> Result<()> {
+        if flush {
+            try!(self.file.flush().map_err(Error::from));
+        }
+        Ok(())
+    }
+}
+
+//
+// Overlay Operations
+//
+// A read-only lower layer under a writable in-memory upper layer. The
+// lower layer is a host directory, or a .tar, .tar.gz or .zip file read
+// as with ArchiveOps. Lower files are copied into a ChunkStore when
+// first opened for writing or changed; deleting a lower entry leaves a
+// whiteout. Nothing is written to the host and nothing is kept in
+// snapshots: the view is disposable.
+//
+enum Lower {
+    Host(PathBuf),
+    Archive(Rc<Archive>, FileAttr),     // with the attributes of made-up directories
+}
+
+impl Lower {
+    // Attributes of a lower directory or regular file, with no inode
+    fn attr(&self, rel: &Path) -> Option<FileAttr> {
+        match *self {
+            Lower::Host(ref host) => match hostfs::metadata(&host.join(rel)) {
+                Ok(ref meta) if meta.is_dir() || meta.is_file() => Some(host_attr(meta, 0)),
+                _ => None,
+            },
+            Lower::Archive(ref archive, ref dir_attr) => {
+                if rel.components().next().is_none() {
+                    return Some(*dir_attr);
+                }
+                for entry in archive.entries().iter() {
+                    if entry.path.as_path() == rel {
+                        return match entry.kind {
+                            FileType::Directory | FileType::RegularFile => Some(entry_attr(entry, 0)),
+                            _ => None,
+                        };
+                    }
+                }
+                // Archives may leave out directory entries
+                if archive.entries().iter().any(|entry| entry.path.starts_with(rel)) {
+                    return Some(*dir_attr);
+                }
+                None
+            },
+        }
+    }
+
+    // Names in the lower directory `rel`
+    fn names(&self, rel: &Path) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        match *self {
+            Lower::Host(ref host) => {
+                let entries = match hostfs::read_dir(&host.join(rel)) {
+                    Ok(entries) => entries,
+                    Err(_) => return Ok(names),     /* directory only exists in the upper layer */
+                };
+                for entry in entries {
+                    let entry = try!(entry.map_err(Error::from));
+                    if let Some(name) = entry.file_name().to_str() {
+                        names.push(name.to_owned());
+                    }
+                }
+            },
+            Lower::Archive(ref archive, _) => {
+                for entry in archive.entries().iter() {
+                    if !entry.path.starts_with(rel) || entry.path.as_path() == rel {
+                        continue;
+                    }
+                    let name = relative_to(&entry.path, rel).components().next()
+                        .and_then(|component| component.as_os_str().to_str())
+                        .map(|name| name.to_owned());
+                    if let Some(name) = name {
+                        if !names.contains(&name) {
+                            names.push(name);
+                        }
+                    }
+                }
+            },
+        }
+        Ok(names)
+    }
+
+    // Contents of the lower file `rel`
+    fn reader(&self, rel: &Path) -> Result<Box<Read>> {
+        match *self {
+            Lower::Host(ref host) => {
+                let file = try!(hostfs::File::open(&host.join(rel)).map_err(Error::from));
+                Ok(Box::new(file))
+            },
+            Lower::Archive(ref archive, _) => {
+                let entry = try!(archive.entries().iter().find(|entry| entry.path.as_path() == rel)
+                                 .ok_or(Error::new(ENOENT)));
+                archive.reader(entry, 0).map_err(Error::from)
+            },
+        }
+    }
+
+    // Read-only handle on the lower file `rel`
+    fn open(&self, rel: &Path) -> Result<RcRefBox<OpenHandler>> {
+        match *self {
+            Lower::Host(ref host) => {
+                let file = try!(hostfs::File::open(&host.join(rel)).map_err(Error::from));
+                Ok(RcRefBox!(PassthroughHandler { file: file }))
+            },
+            Lower::Archive(ref archive, _) => {
+                let entry = try!(archive.entries().iter().find(|entry| entry.path.as_path() == rel)
+                                 .ok_or(Error::new(ENOENT)));
+                Ok(RcRefBox!(ArchiveHandler {
+                    archive: archive.clone(),
+                    entry: entry.clone(),
+                    reader: None,
+                    pos: 0,
+                }))
+            },
+        }
+    }
+}
+
+struct OverlayState {
+    lower: Lower,                   // set on install
+    whiteouts: HashSet<PathBuf>,    // deleted lower entries
+    opaque: HashSet<PathBuf>,       // directories hiding their lower contents
+}
+
+pub struct OverlayOps {
+    prefix: PathBuf,
+    lower: PathBuf,
+    state: RcRef<OverlayState>,         // shared by every node of the overlay
+    upper: Option<RcRef<ChunkStore>>,   // file data, once copied up
+}
+
+// Remove the markers at or below `dir`, giving their paths relative to it
+fn take_markers(markers: &mut HashSet<PathBuf>, dir: &Path) -> Vec<PathBuf> {
+    let taken: Vec<PathBuf> = markers.iter().filter(|path| path.starts_with(dir)).cloned().collect();
+    for path in taken.iter() {
+        markers.remove(path);
+    }
+    taken.iter().map(|path| relative_to(path, dir)).collect()
+}
+
+impl OverlayOps {
+    pub fn new(prefix: &str, lower: &str) -> RcRefBox<Operations> {
+        RcRefBox!(OverlayOps {
+            prefix: PathBuf::from(prefix),
+            lower: PathBuf::from(lower),
+            state: RcRef!(OverlayState {
+                lower: Lower::Host(PathBuf::from(lower)),
+                whiteouts: HashSet::new(),
+                opaque: HashSet::new(),
+            }),
+            upper: None,
+        })
+    }
+
+    fn rel_path(&self, fs: &BasicFileSystem, ino: Inode) -> Result<PathBuf> {
+        let node = try!(fs.find_node(ino).ok_or(Error::new(ENOENT)));
+        Ok(relative_to(&get_path(fs, node), &self.prefix))
+    }
+
+    fn lower_visible(&self, rel: &Path) -> bool {
+        let state = self.state.borrow();
+        if state.whiteouts.contains(rel) {
+            return false;
+        }
+        let mut ancestor = rel.parent();
+        while let Some(dir) = ancestor {
+            if state.opaque.contains(dir) {
+                return false;
+            }
+            ancestor = dir.parent();
+        }
+        true
+    }
+
+    fn lower_attr(&self, rel: &Path) -> Option<FileAttr> {
+        if !self.lower_visible(rel) {
+            return None;
+        }
+        self.state.borrow().lower.attr(rel)
+    }
+
+    fn link_lower_entry(&self, fs: &mut BasicFileSystem, parent_dir: &RcRef<Dir>, name: &str,
+                        attr: &FileAttr) -> Result<Node> {
+        let attr = FileAttr { ino: fs.alloc_inode(), ..*attr };
+        let node = if attr.kind == FileType::Directory {
+            Node::Dir(RcRef!(Dir::new(name, attr, None, self.new_ops())))
+        } else {
+            Node::File(RcRef!(File::new(name, attr, None, self.new_ops())))
+        };
+        try!(fs.link_node(parent_dir, node.clone()));
+        Ok(node)
+    }
+
+    fn copy_up(&mut self, fs: &mut BasicFileSystem, ino: Inode) -> Result<RcRef<ChunkStore>> {
+        if let Some(ref data) = self.upper {
+            return Ok(data.clone());
+        }
+
+        let mut store = ChunkStore::new();
+        let rel = try!(self.rel_path(fs, ino));
+        if self.lower_attr(&rel).is_some() {
+            let mut reader = try!(self.state.borrow().lower.reader(&rel));
+            let mut buf = vec![0; CHUNK_SIZE as usize];
+            let mut offset = 0;
+            loop {
+                let len = try!(reader.read(&mut buf).map_err(Error::from));
+                if len == 0 {
+                    break;
+                }
+                try!(fs.usage().borrow_mut().reserve(store.allocation_for(offset, &buf[..len])));
+                store.write(offset, &buf[..len]);
+                offset += len as u64;
+            }
+            info!("overlay: {:?} copied up", rel);
+        }
+
+        let data = RcRef!(store);
+        self.upper = Some(data.clone());
+        Ok(data)
+    }
+}
+
+impl Operations for OverlayOps {
+    fn name(&self) -> &str {
+        "filesystem.OverlayOps"
+    }
+
+    fn new_ops(&self) -> RcRefBox<Operations> {
+        RcRefBox!(OverlayOps {
+            prefix: self.prefix.clone(),
+            lower: self.lower.clone(),
+            state: self.state.clone(),
+            upper: None,
+        })
+    }
+
+    fn install(&mut self, fs: &mut BasicFileSystem) -> bool {
+        let attr = match hostfs::metadata(&self.lower) {
+            Ok(ref meta) if meta.is_dir() => host_attr(meta, 0),
+            Ok(ref meta) if meta.is_file() && archive::Format::of(&self.lower).is_some() => {
+                let archive = match Archive::open(&self.lower) {
+                    Ok(archive) => Rc::new(archive),
+                    Err(err) => {
+                        error!("install: cannot read archive {:?}: {}", self.lower, err);
+                        return false;
+                    }
+                };
+                let attr = FileAttr { kind: FileType::Directory, perm: 0o755, ..host_attr(meta, 0) };
+                self.state.borrow_mut().lower = Lower::Archive(archive, attr);
+                attr
+            },
+            _ => {
+                error!("install: {:?} is neither a host directory nor an archive", self.lower);
+                return false;
+            }
+        };
+        bind_prefix(fs, &self.prefix, attr, self.new_ops())
+    }
+
+    fn uninstall(&mut self, fs: &mut BasicFileSystem) -> bool {
+        unbind_prefix(fs, &self.prefix);
+        true
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::subtree(&self.prefix)]
+    }
+
+    // Copied-up files and directories keep their attributes in the tree;
+    // lower files follow the lower layer until they are copied up
+    fn getattr(&mut self, fs: &mut BasicFileSystem, node: Node) -> Result<FileAttr> {
+        if let Some(ref data) = self.upper {
+            let data = data.borrow();
+            return Ok(FileAttr { size: data.len(), blocks: data.blocks(), ..node.attr() });
+        }
+        if node.is_dir() {
+            return Ok(node.attr());
+        }
+        let ino = node.attr().ino;
+        let rel = try!(self.rel_path(fs, ino));
+        Ok(match self.lower_attr(&rel) {
+            Some(attr) => FileAttr { ino: ino, ..attr },
+            None => node.attr(),
+        })
+    }
+
+    fn setattr(&mut self, fs: &mut BasicFileSystem, ino: Inode, _set: &SetAttr) -> Result<()> {
+        let is_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).is_dir();
+        if !is_dir {
+            try!(self.copy_up(fs, ino));
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, fs: &mut BasicFileSystem, ino: Inode, size: u64) -> Result<()> {
+        let data = try!(self.copy_up(fs, ino));
+        let released = data.borrow_mut().truncate(size);
+        fs.usage().borrow_mut().release(released);
+        Ok(())
+    }
+
+    // Lower directories would have to be copied up as a whole;
+    // EXDEV makes mv(1) fall back to copy and delete. Markers below
+    // a moved upper directory move with it.
+    fn rename(&mut self, fs: &mut BasicFileSystem, ino: Inode,
+              newparent: Inode, newname: &str) -> Result<()> {
+        let target = {
+            let parent = try!(fs.find_node(newparent).ok_or(Error::new(ENOENT)));
+            get_path(fs, parent).join(newname)
+        };
+        if !target.starts_with(&self.prefix) {
+            return Err(Error::new(EXDEV));
+        }
+        let rel = try!(self.rel_path(fs, ino));
+        let is_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).is_dir();
+        let in_lower = self.lower_attr(&rel).is_some();
+        if is_dir && in_lower {
+            return Err(Error::new(EXDEV));
+        }
+        if !is_dir {
+            try!(self.copy_up(fs, ino));
+        }
+
+        let target = relative_to(&target, &self.prefix);
+        let mut state = self.state.borrow_mut();
+        if is_dir {
+            let state = &mut *state;
+            for markers in vec![&mut state.whiteouts, &mut state.opaque].into_iter() {
+                take_markers(markers, &target);
+                for path in take_markers(markers, &rel).into_iter() {
+                    markers.insert(target.join(path));
+                }
+            }
+        }
+        if in_lower {
+            state.whiteouts.insert(rel);
+        }
+        state.whiteouts.remove(&target);
+        if is_dir {
+            state.opaque.insert(target);
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, fs: &mut BasicFileSystem, ino: Inode, name: &str) -> Result<Option<Node>> {
+        let rel = try!(self.rel_path(fs, ino)).join(name);
+        let attr = match self.lower_attr(&rel) {
+            Some(attr) => attr,
+            None => return Ok(None),
+        };
+        let parent_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).to_dir().clone();
+        self.link_lower_entry(fs, &parent_dir, name, &attr).map(Some)
+    }
+
+    // Merge visible lower entries into the directory; entries already
+    // in the tree (upper or previously merged) take precedence
+    fn readdir(&mut self, fs: &mut BasicFileSystem, ino: Inode) -> Result<()> {
+        let rel = try!(self.rel_path(fs, ino));
+        let parent_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).to_dir().clone();
+        let names = try!(self.state.borrow().lower.names(&rel));
+        for name in names.iter() {
+            if parent_dir.borrow().find_node(name).is_some() {
+                continue;
+            }
+            if let Some(attr) = self.lower_attr(&rel.join(name)) {
+                try!(self.link_lower_entry(fs, &parent_dir, name, &attr));
+            }
+        }
+        Ok(())
+    }
+
+    // Only reached for entries created through the mount
+    fn mknod(&mut self, fs: &mut BasicFileSystem, ino: Inode, _perm: Perm) -> Result<()> {
+        let rel = try!(self.rel_path(fs, ino));
+        let is_dir = try!(fs.find_node(ino).ok_or(Error::new(ENOENT))).is_dir();
+        if !is_dir {
+            self.upper = Some(RcRef!(ChunkStore::new()));
+        }
+        let mut state = self.state.borrow_mut();
+        if state.whiteouts.remove(&rel) && is_dir {
+            state.opaque.insert(rel);
+        }
+        Ok(())
+    }
+
+    fn rmnod(&mut self, fs: &mut BasicFileSystem, ino: Inode) -> Result<()> {
+        let rel = try!(self.rel_path(fs, ino));
+        if let Some(ref data) = self.upper {
+            fs.usage().borrow_mut().release(data.borrow().allocated());
+        }
+        let in_lower = self.lower_attr(&rel).is_some();
+        let mut state = self.state.borrow_mut();
+        take_markers(&mut state.whiteouts, &rel);
+        take_markers(&mut state.opaque, &rel);
+        if in_lower {
+            state.whiteouts.insert(rel);
+        }
+        Ok(())
+    }
+
+    fn persistent(&self) -> bool { false }
+
+    fn open(&mut self, fs: &mut BasicFileSystem, ino: Inode, flags: Mode)
+        -> Result<RcRefBox<OpenHandler>>
+    {
+        if self.upper.is_none() && flags as c_int & ACCMODE == O_RDONLY {
+            let rel = try!(self.rel_path(fs, ino));
+            if self.lower_attr(&rel).is_some() {
+                return self.state.borrow().lower.open(&rel);
+            }
+        }
+        let data = try!(self.copy_up(fs, ino));
+        Ok(FileHandler::open(ino, flags, data, fs.usage()))
+    }
+}
+
+//
+// Archive Operations
+//
+// A .tar, .tar.gz or .zip file mounted read-only at `prefix`. The
+// whole tree is built from the archive index on install; file data
+// stays in the archive and is streamed from it on read.
+//
+pub struct ArchiveOps {
+    prefix: PathBuf,
+    path: PathBuf,
+    archive: Option<Rc<Archive>>,       // set on install
+    entry: Option<archive::Entry>,      // None for directories
+}
+
+impl ArchiveOps {
+    pub fn new(prefix: &str, path: &str) -> RcRefBox<Operations> {
+        RcRefBox!(ArchiveOps {
+            prefix: PathBuf::from(prefix),
+            path: PathBuf::from(path),
+            archive: None,
+            entry: None,
+        })
+    }
+
+    fn with_entry(&self, entry: Option<archive::Entry>) -> RcRefBox<Operations> {
+        RcRefBox!(ArchiveOps {
+            prefix: self.prefix.clone(),
+            path: self.path.clone(),
+            archive: self.archive.clone(),
+            entry: entry,
+        })
+    }
+
+    // Archives may leave out directory entries; missing
+    // parents are made up with the attributes of `root`
+    fn link_entry(&self, fs: &mut BasicFileSystem, root: &RcRef<Dir>,
+                  entry: &archive::Entry) -> Result<()> {
+        let name = try!(entry.path.file_name().and_then(|name| name.to_str()).ok_or(Error::new(EINVAL)));
+        let mut dir = root.clone();
+        for component in entry.path.parent().unwrap_or(Path::new("")).components() {
+            let part = try!(component.as_os_str().to_str().ok_or(Error::new(EINVAL)));
+            let next = dir.borrow().find_node(part).cloned();
+            dir = match next {
+                Some(Node::Dir(next)) => next,
+                Some(Node::File(_)) => return Err(Error::new(ENOTDIR)),
+                None => {
+                    let attr = FileAttr { ino: fs.alloc_inode(), ..root.borrow().attr().clone() };
+                    let next = RcRef!(Dir::new(part, attr, None, self.with_entry(None)));
+                    try!(fs.link_node(&dir, Node::Dir(next.clone())));
+                    next
+                }
+            };
+        }
+
+        let attr = entry_attr(entry, fs.alloc_inode());
+        let existing = dir.borrow().find_node(name).cloned();
+        let node = match (existing, entry.kind) {
+            (Some(Node::Dir(existing)), FileType::Directory) => {
+                persist::restore_dir_attr(&existing, &attr);
+                return Ok(());
+            },
+            (Some(_), _) => return Err(Error::new(EEXIST)),
+            (None, FileType::Directory) =>
+                Node::Dir(RcRef!(Dir::new(name, attr, None, self.with_entry(None)))),
+            (None, FileType::Symlink) =>
+                Node::File(RcRef!(File::symlink(name, attr, None, self.with_entry(Some(entry.clone()))))),
+            (None, _) =>
+                Node::File(RcRef!(File::new(name, attr, None, self.with_entry(Some(entry.clone()))))),
+        };
+        fs.link_node(&dir, node)
+    }
+}
+
+fn entry_attr(entry: &archive::Entry, ino: Inode) -> FileAttr {
+    let mtime = Timespec::new(entry.mtime, 0);
+    FileAttr {
+        ino: ino,
+        size: entry.size,
+        blocks: (entry.size + 511) / 512,
+        atime: mtime, mtime: mtime, ctime: mtime, crtime: mtime,
+        kind: entry.kind,
+        perm: entry.perm,
+        uid: entry.uid, gid: entry.gid,
+        ..fileattr_new()
+    }
+}
+
+impl Operations for ArchiveOps {
+    fn name(&self) -> &str {
+        "filesystem.ArchiveOps"
+    }
+
+    fn new_ops(&self) -> RcRefBox<Operations> {
+        self.with_entry(None)
+    }
+
+    // The archive does not change under the mount
+    fn entry_ttl(&self) -> Timespec { STATIC_TTL }
+    fn attr_ttl(&self) -> Timespec { STATIC_TTL }
+
+    fn install(&mut self, fs: &mut BasicFileSystem) -> bool {
+        let meta = match hostfs::metadata(&self.path) {
+            Ok(ref meta) if meta.is_file() => meta.clone(),
+            _ => {
+                error!("install: {:?} is not a host file", self.path);
+                return false;
+            }
+        };
+        let archive = match Archive::open(&self.path) {
+            Ok(archive) => Rc::new(archive),
+            Err(err) => {
+                error!("install: cannot read archive {:?}: {}", self.path, err);
+                return false;
+            }
+        };
+        self.archive = Some(archive.clone());
+
+        let attr = FileAttr { perm: 0o555, ..host_attr(&meta, 0) };
+        if !bind_prefix(fs, &self.prefix, attr, self.with_entry(None)) {
+            return false;
+        }
+        let root = match fs.lookup_path(&self.prefix) {
+            Some(node) => node.to_dir().clone(),
+            None => return false,
+        };
+        for entry in archive.entries().iter() {
+            if let Err(err) = self.link_entry(fs, &root, entry) {
+                warn!("install: {:?} in {:?} skipped ({})", entry.path, self.path, err);
+            }
+        }
+        info!("install: {} entries of {:?} at {:?}",
+            archive.entries().len(), self.path, self.prefix);
+        true
+    }
+
+    fn uninstall(&mut self, fs: &mut BasicFileSystem) -> bool {
+        unbind_prefix(fs, &self.prefix);
+        true
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::subtree(&self.prefix)]
+    }
+
+    fn setattr(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _set: &SetAttr) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn rename(&mut self, _fs: &mut BasicFileSystem, _ino: Inode,
+              _newparent: Inode, _newname: &str) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn mknod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _perm: Perm) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn rmnod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn truncate(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _size: u64) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn readlink(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<Vec<u8>> {
+        match self.entry {
+            Some(archive::Entry { target: Some(ref target), .. }) => Ok(target.clone().into_bytes()),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    // Rebuilt from the archive on every mount
+    fn persistent(&self) -> bool { false }
+
+    fn open(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, flags: Mode)
+        -> Result<RcRefBox<OpenHandler>>
+    {
+        if flags as c_int & ACCMODE != O_RDONLY {
+            return Err(Error::new(EROFS));
+        }
+        match (&self.archive, &self.entry) {
+            (&Some(ref archive), &Some(ref entry)) => Ok(RcRefBox!(ArchiveHandler {
+                archive: archive.clone(),
+                entry: entry.clone(),
+                reader: None,
+                pos: 0,
+            })),
+            _ => Err(Error::new(EISDIR)),
+        }
+    }
+}
+
+struct ArchiveHandler {
+    archive: Rc<Archive>,
+    entry: archive::Entry,
+    reader: Option<Box<Read>>,  // positioned at `pos`
+    pos: u64,
+}
+
+impl OpenHandler for ArchiveHandler {
+    fn name(&self) -> &str {
+        "ArchiveOps.ArchiveHandler"
+    }
+
+    fn direct_io(&self) -> bool { false }
+    fn keep_cache(&self) -> bool { true }
+
+    // Reads going forward continue the current stream;
+    // a read before it starts over from the archive
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        if offset >= self.entry.size {
+            return Ok(Vec::new());
+        }
+        if self.reader.is_none() || offset < self.pos {
+            self.reader = Some(try!(self.archive.reader(&self.entry, offset).map_err(Error::from)));
+            self.pos = offset;
+        }
+        let mut buf = Vec::new();
+        {
+            // unwrap: set above
+            let reader = self.reader.as_mut().unwrap();
+            if offset > self.pos {
+                try!(archive::skip(reader, offset - self.pos).map_err(Error::from));
+            }
+            try!(reader.take(size).read_to_end(&mut buf).map_err(Error::from));
+        }
+        self.pos = offset + buf.len() as u64;
+        Ok(buf)
+    }
+
+    fn write(&mut self, _data: &[u8], _offset: u64, _size: u64) -> Result<u64> {
+        Err(Error::new(EBADF))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs as hostfs;
+    use std::io::prelude::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use super::{PassthroughOps, OverlayOps};
+    use super::libc::consts::os::posix88::*;
+    use super::time::Timespec;
+    use core::SetAttr;
+    use harness::Harness;
+    use harness::fixture::{TempDir, names};
+
+    fn host_tree(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        hostfs::File::create(dir.join("f")).unwrap();
+        hostfs::create_dir(dir.join("d")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn passthrough_unlink_and_rmdir() {
+        let host = host_tree("passthrough");
+        let mut h = Harness::new();
+        h.fs().register_ops(10, PassthroughOps::new("/p", host.path().to_str().unwrap()));
+        assert_eq!(names(&mut h, "/p"), vec!["d", "f"]);
+        h.unlink("/p/f").unwrap();
+        h.rmdir("/p/d").unwrap();
+        assert!(!host.join("f").exists());
+        assert!(!host.join("d").exists());
+        assert_eq!(h.lookup("/p/f").err(), Some(ENOENT));
+    }
+
+    // A chmod leaves alone what changed on the host since the lookup
+    #[test]
+    fn passthrough_sets_only_what_was_asked() {
+        let host = host_tree("passthrough-setattr");
+        let mut h = Harness::new();
+        h.fs().register_ops(10, PassthroughOps::new("/p", host.path().to_str().unwrap()));
+        let past = SetAttr { mtime: Some(Timespec::new(1000, 0)), ..SetAttr::default() };
+        h.setattr("/p/f", &past).unwrap();
+
+        hostfs::OpenOptions::new().write(true).open(host.join("f")).unwrap()
+            .write_all(b"changed").unwrap();
+        let chmod = SetAttr { mode: Some(0o600), ..SetAttr::default() };
+        h.setattr("/p/f", &chmod).unwrap();
+        let meta = hostfs::metadata(host.join("f")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert!(meta.mtime() != 1000);
+        assert_eq!(meta.len(), 7);
+    }
+
+    #[test]
+    fn overlay_unlink_and_rmdir() {
+        let lower = host_tree("overlay");
+        let mut h = Harness::new();
+        h.fs().register_ops(10, OverlayOps::new("/o", lower.path().to_str().unwrap()));
+        assert_eq!(names(&mut h, "/o"), vec!["d", "f"]);
+        h.unlink("/o/f").unwrap();
+        h.rmdir("/o/d").unwrap();
+        assert_eq!(names(&mut h, "/o"), Vec::<String>::new());
+        assert_eq!(h.lookup("/o/f").err(), Some(ENOENT));
+        assert!(lower.join("f").exists());
+        assert!(lower.join("d").exists());
+    }
 }
@@ -0,0 +1,352 @@
+
+extern crate libc;
+extern crate time;
+extern crate fuse;
+
+use std::io;
+use std::io::prelude::*;
+use std::fs as hostfs;
+use std::rc::Rc;
+use std::cell::RefCell;
+use self::time::Timespec;
+use self::libc::consts::os::posix88::*; /* POSIX errno */
+use self::fuse::FileAttr;
+
+use fs::*;
+use common::*;
+use core::BasicFileSystem;
+
+//
+// Snapshot format
+//
+//  "WLFS" version:u32 seq:u64 <root dir record>
+//
+// seq is that of the last journal record the tree includes.
+//  record := kind:u8 name:str ops:str attr data:bytes [children END]
+//
+// Directory records are followed by their children and an END marker.
+// `data` is whatever Operations::snapshot() returned for the node.
+// Integers are big-endian; str and bytes are length-prefixed (u64).
+//
+const MAGIC: &'static [u8] = b"WLFS";
+const VERSION: u32 = 2;
+
+const NODE_FILE: u8 = 0;
+const NODE_DIR:  u8 = 1;
+const NODE_END:  u8 = 2;
+
+//
+// Encoding helpers
+//
+pub fn write_u8<W: Write>(w: &mut W, n: u8) -> io::Result<()> {
+    w.write_all(&[n])
+}
+
+pub fn write_u16<W: Write>(w: &mut W, n: u16) -> io::Result<()> {
+    w.write_all(&[(n >> 8) as u8, n as u8])
+}
+
+pub fn write_u32<W: Write>(w: &mut W, n: u32) -> io::Result<()> {
+    try!(write_u16(w, (n >> 16) as u16));
+    write_u16(w, n as u16)
+}
+
+pub fn write_u64<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    try!(write_u32(w, (n >> 32) as u32));
+    write_u32(w, n as u32)
+}
+
+pub fn write_bytes<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    try!(write_u64(w, data.len() as u64));
+    w.write_all(data)
+}
+
+pub fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write_bytes(w, s.as_bytes())
+}
+
+pub fn write_timespec<W: Write>(w: &mut W, t: Timespec) -> io::Result<()> {
+    try!(write_u64(w, t.sec as u64));
+    write_u32(w, t.nsec as u32)
+}
+
+pub fn write_attr<W: Write>(w: &mut W, attr: &FileAttr) -> io::Result<()> {
+    try!(write_u64(w, attr.size));
+    try!(write_timespec(w, attr.atime));
+    try!(write_timespec(w, attr.mtime));
+    try!(write_timespec(w, attr.ctime));
+    try!(write_timespec(w, attr.crtime));
+    try!(write_u16(w, attr.perm));
+    try!(write_u32(w, attr.uid));
+    try!(write_u32(w, attr.gid));
+    try!(write_u32(w, attr.rdev));
+    write_u32(w, attr.flags)
+}
+
+pub fn read_exact<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        match try!(r.read(&mut buf[done..])) {
+            0 => return Err(io::Error::new(io::ErrorKind::Other, "unexpected end of file")),
+            n => done += n,
+        }
+    }
+    Ok(())
+}
+
+pub fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    try!(read_exact(r, &mut buf));
+    Ok(buf[0])
+}
+
+pub fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    try!(read_exact(r, &mut buf));
+    Ok((buf[0] as u16) << 8 | buf[1] as u16)
+}
+
+pub fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let hi = try!(read_u16(r)) as u32;
+    let lo = try!(read_u16(r)) as u32;
+    Ok(hi << 16 | lo)
+}
+
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let hi = try!(read_u32(r)) as u64;
+    let lo = try!(read_u32(r)) as u64;
+    Ok(hi << 32 | lo)
+}
+
+// Data is taken as it is read, so a corrupt length asks for no more
+// memory than the input holds; EIO if it runs out first
+pub fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = try!(read_u64(r));
+    let mut buf = Vec::new();
+    try!(r.by_ref().take(len).read_to_end(&mut buf));
+    if (buf.len() as u64) < len {
+        return Err(io::Error::from_raw_os_error(EIO));
+    }
+    Ok(buf)
+}
+
+pub fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let buf = try!(read_bytes(r));
+    String::from_utf8(buf).or(Err(io::Error::new(io::ErrorKind::Other, "invalid name")))
+}
+
+pub fn read_timespec<R: Read>(r: &mut R) -> io::Result<Timespec> {
+    let sec = try!(read_u64(r)) as i64;
+    let nsec = try!(read_u32(r)) as i32;
+    Ok(Timespec { sec: sec, nsec: nsec })
+}
+
+pub fn read_attr<R: Read>(r: &mut R) -> io::Result<FileAttr> {
+    let size = try!(read_u64(r));
+    let atime = try!(read_timespec(r));
+    let mtime = try!(read_timespec(r));
+    let ctime = try!(read_timespec(r));
+    let crtime = try!(read_timespec(r));
+    let perm = try!(read_u16(r));
+    let uid = try!(read_u32(r));
+    let gid = try!(read_u32(r));
+    let rdev = try!(read_u32(r));
+    let flags = try!(read_u32(r));
+    Ok(FileAttr {
+        size: size,
+        atime: atime, mtime: mtime, ctime: ctime, crtime: crtime,
+        perm: perm, uid: uid, gid: gid,
+        rdev: rdev, flags: flags,
+        ..fileattr_new()
+    })
+}
+
+//
+// Save
+//
+pub fn save(fs: &mut BasicFileSystem, path: &Path) -> Result<()> {
+    // Write aside and rename, so a failed save keeps the previous snapshot
+    let tmp = match path.file_name() {
+        Some(name) => {
+            let mut name = name.to_os_string();
+            name.push(".tmp");
+            path.with_file_name(name)
+        },
+        None => return Err(Error::new(EINVAL).with_path(path)),
+    };
+    {
+        let file = try!(hostfs::File::create(&tmp).map_err(Error::from));
+        let mut w = io::BufWriter::new(file);
+        try!(w.write_all(MAGIC).map_err(Error::from));
+        try!(write_u32(&mut w, VERSION).map_err(Error::from));
+        try!(write_u64(&mut w, fs.journal_seq()).map_err(Error::from));
+        let root = Node::Dir(fs.root());
+        try!(save_node(fs, &mut w, &root).map_err(Error::from));
+        try!(w.flush().map_err(Error::from));
+    }
+    try!(hostfs::rename(&tmp, path).map_err(Error::from));
+    info!("save: snapshot written to {:?}", path);
+    Ok(())
+}
+
+pub fn save_node<W: Write>(fs: &mut BasicFileSystem, w: &mut W, node: &Node) -> io::Result<()> {
+    let attr = node.attr();
+    let _ops = node.ops();
+    let persistent = _ops.borrow().persistent();
+    let snapshot = if persistent { _ops.borrow_mut().snapshot(fs, attr.ino) } else { None };
+    let data = match snapshot {
+        Some(data) => data,
+        None => {
+            info!("save: {} skipped by {}", node.name(), _ops.borrow().name());
+            return Ok(());
+        }
+    };
+
+    try!(write_u8(w, if node.is_dir() { NODE_DIR } else { NODE_FILE }));
+    try!(write_str(w, &node.name()));
+    try!(write_str(w, _ops.borrow().name()));
+    try!(write_attr(w, &attr));
+    try!(write_bytes(w, &data));
+
+    if node.is_dir() {
+        let children: Vec<Node> = node.to_dir().borrow().nodes().values().cloned().collect();
+        for child in children.iter() {
+            try!(save_node(fs, w, child));
+        }
+        try!(write_u8(w, NODE_END));
+    }
+    Ok(())
+}
+
+//
+// Restore
+//
+struct Record {
+    name: String,
+    ops: String,
+    attr: FileAttr,
+    data: Vec<u8>,
+}
+
+fn read_record<R: Read>(r: &mut R) -> io::Result<Record> {
+    let name = try!(read_str(r));
+    let ops = try!(read_str(r));
+    let attr = try!(read_attr(r));
+    let data = try!(read_bytes(r));
+    Ok(Record { name: name, ops: ops, attr: attr, data: data })
+}
+
+// A missing snapshot file is not an error: there is nothing to restore.
+// Gives the seq of the last journal record the snapshot includes.
+pub fn restore(fs: &mut BasicFileSystem, path: &Path) -> Result<u64> {
+    let file = match hostfs::File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(Error::from(err)),
+    };
+    let mut r = io::BufReader::new(file);
+
+    let mut magic = [0; 4];
+    try!(read_exact(&mut r, &mut magic).map_err(Error::from));
+    let version = try!(read_u32(&mut r).map_err(Error::from));
+    if &magic[..] != MAGIC || version == 0 || version > VERSION {
+        error!("restore: {:?} is not a wlfs snapshot", path);
+        return Err(Error::new(EINVAL));
+    }
+    // Version 1 snapshots predate journal seqs
+    let seq = if version >= 2 { try!(read_u64(&mut r).map_err(Error::from)) } else { 0 };
+
+    if try!(read_u8(&mut r).map_err(Error::from)) != NODE_DIR {
+        return Err(Error::new(EINVAL));
+    }
+    let record = try!(read_record(&mut r).map_err(Error::from));
+    let root = fs.root();
+    restore_dir_attr(&root, &record.attr);
+    try!(restore_children(fs, &mut r, Some(root)).map_err(Error::from));
+
+    info!("restore: snapshot loaded from {:?}", path);
+    Ok(seq)
+}
+
+// Keeps the directory's own inode, kind and link count
+pub fn restore_dir_attr(dir: &RcRef<Dir>, saved: &FileAttr) {
+    let mut dir = dir.borrow_mut();
+    let attr = dir.attr_mut();
+    attr.atime = saved.atime;
+    attr.mtime = saved.mtime;
+    attr.ctime = saved.ctime;
+    attr.crtime = saved.crtime;
+    attr.perm = saved.perm;
+    attr.uid = saved.uid;
+    attr.gid = saved.gid;
+}
+
+// Children of a skipped directory are read and dropped (parent == None)
+fn restore_children<R: Read>(fs: &mut BasicFileSystem, r: &mut R, parent: Option<RcRef<Dir>>)
+    -> io::Result<()>
+{
+    loop {
+        let kind = try!(read_u8(r));
+        if kind == NODE_END {
+            return Ok(());
+        }
+        if kind != NODE_FILE && kind != NODE_DIR {
+            return Err(io::Error::new(io::ErrorKind::Other, "bad record"));
+        }
+        let record = try!(read_record(r));
+        let node = match parent {
+            Some(ref dir) => restore_node(fs, dir, kind == NODE_DIR, record),
+            None => None,
+        };
+        if kind == NODE_DIR {
+            try!(restore_children(fs, r, node.map(|n| n.to_dir().clone())));
+        }
+    }
+}
+
+fn restore_node(fs: &mut BasicFileSystem, parent_dir: &RcRef<Dir>, is_dir: bool, record: Record)
+    -> Option<Node>
+{
+    // Directories created before the restore (e.g. by install hooks) are merged
+    let existing = parent_dir.borrow().find_node(&record.name).cloned();
+    if let Some(node) = existing {
+        if is_dir && node.is_dir() {
+            restore_dir_attr(node.to_dir(), &record.attr);
+            return Some(node);
+        }
+        warn!("restore: {} already exists, skipped", record.name);
+        return None;
+    }
+
+    let ops = match fs.find_ops(&record.ops) {
+        Some(proto) => {
+            let ops = proto.borrow().new_ops();
+            ops
+        },
+        None => {
+            warn!("restore: {} needs {} which is not registered, skipped",
+                record.name, record.ops);
+            return None;
+        }
+    };
+
+    let attr = FileAttr { ino: fs.alloc_inode(), ..record.attr };
+    let node = if is_dir {
+        Node::Dir(RcRef!(Dir::new(&record.name, attr, None, ops.clone())))
+    } else {
+        Node::File(RcRef!(File::new(&record.name, attr, None, ops.clone())))
+    };
+    if fs.link_node(parent_dir, node.clone()).is_err() {
+        return None;
+    }
+
+    let result = ops.borrow_mut().restore(fs, attr.ino, &record.data);
+    match result {
+        Ok(_) => Some(node),
+        Err(err) => {
+            warn!("restore: {} failed to restore ({}), skipped", record.name, err);
+            fs.unlink_node(parent_dir, &node);
+            None
+        }
+    }
+}
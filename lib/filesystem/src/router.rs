@@ -0,0 +1,390 @@
+
+extern crate fuse;
+extern crate regex;
+
+use std::fmt;
+use std::collections::HashMap;
+use std::path::Component;
+use self::fuse::FileType;
+use self::regex::Regex;
+
+use common::*;
+use ops::Operations;
+use core::Priority;
+
+//
+// Path routing
+//
+// Ops claim the nodes they serve with routes: a glob or a regex over the
+// path, optionally limited to one kind of node. A path gets the ops of
+// the first matching rule in priority order; rules of equal priority are
+// tried in registration order.
+//
+// Rules are filed under the literal components their pattern starts
+// with, so a lookup only tries the rules found along the path instead of
+// every rule registered.
+//
+
+#[derive(Clone)]
+enum Pattern {
+    Glob(Vec<String>),
+    Regex(Vec<String>, Regex),  // literal prefix, expression
+    Target,                     // ask Operations::is_target()
+}
+
+#[derive(Clone)]
+pub struct Route {
+    source: String,
+    pattern: Pattern,
+    kind: Option<FileType>,
+}
+
+impl Route {
+    // "*" and "?" match within a component, "**" any number of components
+    pub fn glob(pattern: &str) -> Route {
+        Route {
+            source: pattern.to_owned(),
+            pattern: Pattern::Glob(components(Path::new(pattern))),
+            kind: None,
+        }
+    }
+
+    // `prefix` and everything below it
+    pub fn subtree<P: AsRef<Path>>(prefix: P) -> Route {
+        let mut names = components(prefix.as_ref());
+        names.push("**".to_owned());
+        Route {
+            source: format!("{}/**", prefix.as_ref().display()),
+            pattern: Pattern::Glob(names),
+            kind: None,
+        }
+    }
+
+    // `re` is matched against the whole path and should be anchored.
+    // It may only match paths below the literal `prefix`, which is where
+    // the rule is filed.
+    pub fn regex(prefix: &str, re: Regex) -> Route {
+        Route {
+            source: format!("{}", re),
+            pattern: Pattern::Regex(components(Path::new(prefix)), re),
+            kind: None,
+        }
+    }
+
+    // Falls back on Operations::is_target(), for ops without routes
+    pub fn target() -> Route {
+        Route { source: "is_target()".to_owned(), pattern: Pattern::Target, kind: None }
+    }
+
+    pub fn kind(mut self, kind: FileType) -> Route {
+        self.kind = Some(kind);
+        self
+    }
+
+    // Literal leading components, where the rule is filed
+    fn prefix(&self) -> Vec<String> {
+        match self.pattern {
+            Pattern::Glob(ref names) =>
+                names.iter().take_while(|name| !is_wild(name)).cloned().collect(),
+            Pattern::Regex(ref prefix, _) => prefix.clone(),
+            Pattern::Target => Vec::new(),
+        }
+    }
+
+    fn accepts(&self, kind: FileType) -> bool {
+        self.kind.map_or(true, |k| k == kind)
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pattern = match self.pattern {
+            Pattern::Glob(_) => "glob",
+            Pattern::Regex(..) => "regex",
+            Pattern::Target => "ops",
+        };
+        let kind = self.kind.map_or("any", kind_name);
+        write!(f, "{} {} {}", pattern, self.source, kind)
+    }
+}
+
+pub fn kind_name(kind: FileType) -> &'static str {
+    match kind {
+        FileType::NamedPipe => "fifo",
+        FileType::CharDevice => "chr",
+        FileType::BlockDevice => "blk",
+        FileType::Directory => "dir",
+        FileType::RegularFile => "file",
+        FileType::Symlink => "symlink",
+    }
+}
+
+pub fn parse_kind(name: &str) -> Option<FileType> {
+    match name {
+        "fifo" => Some(FileType::NamedPipe),
+        "chr" => Some(FileType::CharDevice),
+        "blk" => Some(FileType::BlockDevice),
+        "dir" => Some(FileType::Directory),
+        "file" => Some(FileType::RegularFile),
+        "symlink" => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+struct Rule {
+    priority: Priority,
+    route: Route,
+    ops: RcRefBox<Operations>,
+}
+
+impl Rule {
+    fn matches(&self, path: &Path, kind: FileType) -> bool {
+        self.route.accepts(kind) && self.matches_path(path, kind)
+    }
+
+    fn matches_path(&self, path: &Path, kind: FileType) -> bool {
+        match self.route.pattern {
+            Pattern::Glob(ref pattern) => {
+                let names = components(path);
+                let names: Vec<&str> = names.iter().map(|name| &name[..]).collect();
+                match_glob(pattern, &names)
+            },
+            Pattern::Regex(_, ref re) => re.is_match(path.to_str().unwrap()),
+            Pattern::Target => self.ops.borrow_mut().is_target(path, kind),
+        }
+    }
+
+    // Whether some path of some kind may match both rules. Globs are
+    // compared exactly; a regex is only tried against the paths a glob
+    // spells out, and two regexes are never reported.
+    fn overlaps(&self, other: &Rule) -> bool {
+        if let (Some(a), Some(b)) = (self.route.kind, other.route.kind) {
+            if a != b {
+                return false;
+            }
+        }
+        match (&self.route.pattern, &other.route.pattern) {
+            (&Pattern::Glob(ref a), &Pattern::Glob(ref b)) => globs_meet(a, b),
+            (&Pattern::Glob(ref glob), &Pattern::Regex(_, ref re)) |
+            (&Pattern::Regex(_, ref re), &Pattern::Glob(ref glob)) =>
+                re.is_match(&sample_path(glob)),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.priority, self.ops.borrow().name(), self.route)
+    }
+}
+
+// Rules filed under a literal path prefix
+struct Index {
+    rules: Vec<usize>,
+    children: HashMap<String, Index>,
+}
+
+impl Index {
+    fn new() -> Index {
+        Index { rules: Vec::new(), children: HashMap::new() }
+    }
+
+    fn insert(&mut self, prefix: &[String], rule: usize) {
+        match prefix.first() {
+            None => self.rules.push(rule),
+            Some(name) => self.children.entry(name.clone())
+                .or_insert_with(Index::new).insert(&prefix[1..], rule),
+        }
+    }
+}
+
+pub struct Router {
+    rules: Vec<Rule>,   // in the order they are tried
+    index: Index,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { rules: Vec::new(), index: Index::new() }
+    }
+
+    // Rules overlapping a rule of the same priority are logged, the
+    // earlier one wins there
+    pub fn add(&mut self, p: Priority, routes: Vec<Route>, ops: RcRefBox<Operations>) {
+        for route in routes.into_iter() {
+            let rule = Rule { priority: p, route: route, ops: ops.clone() };
+            for other in self.rules.iter().filter(|other| other.priority == p) {
+                if other.overlaps(&rule) {
+                    warn!("router: {} overlaps {}", rule, other);
+                }
+            }
+            // After the rules of the same or a higher priority
+            let at = self.rules.iter().position(|other| other.priority < p)
+                .unwrap_or(self.rules.len());
+            self.rules.insert(at, rule);
+        }
+        self.reindex();
+    }
+
+    // Drop the rules of the ops named `name`
+    pub fn remove(&mut self, name: &str) {
+        self.rules.retain(|rule| rule.ops.borrow().name() != name);
+        self.reindex();
+    }
+
+    fn reindex(&mut self) {
+        let mut index = Index::new();
+        for (i, rule) in self.rules.iter().enumerate() {
+            index.insert(&rule.route.prefix(), i);
+        }
+        self.index = index;
+    }
+
+    // Rules filed along `path`, in the order they are tried
+    fn candidates(&self, path: &Path) -> Vec<usize> {
+        let mut found = self.index.rules.clone();
+        let mut node = &self.index;
+        for name in components(path).iter() {
+            match node.children.get(name) {
+                Some(child) => {
+                    found.extend(child.rules.iter().cloned());
+                    node = child;
+                },
+                None => break,
+            }
+        }
+        found.sort();
+        found
+    }
+
+    pub fn route(&self, path: &Path, kind: FileType) -> Option<RcRefBox<Operations>> {
+        self.candidates(path).into_iter()
+            .map(|i| &self.rules[i])
+            .find(|rule| rule.matches(path, kind))
+            .map(|rule| rule.ops.clone())
+    }
+
+    // How route() decides for `path`, one line per rule it tries
+    pub fn explain(&self, path: &Path, kind: FileType) -> Vec<String> {
+        let candidates = self.candidates(path);
+        let mut lines = vec![
+            format!("path {} {}", path.display(), kind_name(kind)),
+            format!("tried {} of {} rules", candidates.len(), self.rules.len()),
+        ];
+        let mut selected: Option<&Rule> = None;
+        for &i in candidates.iter() {
+            let rule = &self.rules[i];
+            let verdict = if !rule.route.accepts(kind) {
+                "other kind"
+            } else if !rule.matches_path(path, kind) {
+                "no match"
+            } else if selected.is_some() {
+                "shadowed"
+            } else {
+                selected = Some(rule);
+                "selected"
+            };
+            lines.push(format!("{}: {}", rule, verdict));
+        }
+        lines.push(match selected {
+            Some(rule) => format!("-> {}", rule.ops.borrow().name()),
+            None => "-> none".to_owned(),
+        });
+        lines
+    }
+
+    // Every rule, in the order they are tried
+    pub fn rules(&self) -> Vec<String> {
+        self.rules.iter().map(|rule| format!("{}", rule)).collect()
+    }
+
+    // Pairs of rules of the same priority that may match the same path
+    pub fn overlaps(&self) -> Vec<(String, String)> {
+        let mut pairs = Vec::new();
+        for (i, a) in self.rules.iter().enumerate() {
+            for b in self.rules[i + 1..].iter().take_while(|b| b.priority == a.priority) {
+                if a.overlaps(b) {
+                    pairs.push((format!("{}", a), format!("{}", b)));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+fn components(path: &Path) -> Vec<String> {
+    path.components().filter_map(|c| match c {
+        Component::Normal(name) => name.to_str().map(|name| name.to_owned()),
+        _ => None,
+    }).collect()
+}
+
+fn is_wild(name: &str) -> bool {
+    name.contains('*') || name.contains('?')
+}
+
+fn match_glob(pattern: &[String], names: &[&str]) -> bool {
+    match pattern.first() {
+        None => names.is_empty(),
+        Some(p) if &p[..] == "**" =>
+            match_glob(&pattern[1..], names) ||
+            (!names.is_empty() && match_glob(pattern, &names[1..])),
+        Some(p) =>
+            !names.is_empty() &&
+            match_name(p.as_bytes(), names[0].as_bytes()) &&
+            match_glob(&pattern[1..], &names[1..]),
+    }
+}
+
+fn match_name(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&b'*') =>
+            match_name(&pattern[1..], name) ||
+            (!name.is_empty() && match_name(pattern, &name[1..])),
+        Some(&c) =>
+            !name.is_empty() && (c == b'?' || c == name[0]) &&
+            match_name(&pattern[1..], &name[1..]),
+    }
+}
+
+// Whether two globs match a common path
+fn globs_meet(a: &[String], b: &[String]) -> bool {
+    match (a.first(), b.first()) {
+        (None, None) => true,
+        (Some(x), _) if &x[..] == "**" =>
+            globs_meet(&a[1..], b) || (!b.is_empty() && globs_meet(a, &b[1..])),
+        (_, Some(y)) if &y[..] == "**" =>
+            globs_meet(a, &b[1..]) || (!a.is_empty() && globs_meet(&a[1..], b)),
+        (Some(x), Some(y)) =>
+            names_meet(x.as_bytes(), y.as_bytes()) && globs_meet(&a[1..], &b[1..]),
+        _ => false,
+    }
+}
+
+fn names_meet(a: &[u8], b: &[u8]) -> bool {
+    match (a.first(), b.first()) {
+        (None, None) => true,
+        (Some(&b'*'), _) =>
+            names_meet(&a[1..], b) || (!b.is_empty() && names_meet(a, &b[1..])),
+        (_, Some(&b'*')) =>
+            names_meet(a, &b[1..]) || (!a.is_empty() && names_meet(&a[1..], b)),
+        (Some(&x), Some(&y)) =>
+            (x == b'?' || y == b'?' || x == y) && names_meet(&a[1..], &b[1..]),
+        _ => false,
+    }
+}
+
+// A path matching `glob`, with "**" matching nothing
+fn sample_path(glob: &[String]) -> String {
+    let mut path = String::new();
+    for name in glob.iter().filter(|name| &name[..] != "**") {
+        path.push('/');
+        path.push_str(&name.replace("*", "").replace("?", "x"));
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
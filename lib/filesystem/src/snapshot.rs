@@ -0,0 +1,377 @@
+
+extern crate libc;
+extern crate time;
+extern crate fuse;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use self::time::Timespec;
+use self::fuse::{FileType, FileAttr};
+use self::libc::c_int;
+use self::libc::consts::os::posix88::*; /* POSIX errno, open flags */
+
+use fs::*;
+use common::*;
+use chunk::ChunkStore;
+use ops::{Operations, OpenHandler, ACCMODE, STATIC_TTL};
+use router::Route;
+use ctl::{CtlCommands, CtlOps};
+use core::{BasicFileSystem, SetAttr};
+
+/* Where snapshots are published, one directory per snapshot */
+pub const SNAPSHOT_DIR: &'static str = "/.snapshots";
+const CTL_NAME: &'static str = "ctl";
+
+// A frozen subtree. File data shares its chunks with the
+// live node it was taken from until either side writes.
+#[derive(Clone, Debug)]
+pub struct Frozen {
+    pub name: String,
+    pub attr: FileAttr,
+    pub data: ChunkStore,
+    pub children: Vec<Frozen>,
+}
+
+#[derive(Debug)]
+pub struct Snapshot {
+    pub name: String,
+    pub source: PathBuf,
+    pub taken: Timespec,
+    pub tree: Frozen,
+}
+
+// Freeze `node` and its subtree through Operations::freeze()
+pub fn freeze(fs: &mut BasicFileSystem, node: &Node) -> Option<Frozen> {
+    let attr = node.attr();
+    let data = {
+        let _ops = node.ops();
+        let mut ops = _ops.borrow_mut();
+        match ops.freeze(fs, attr.ino) {
+            Some(data) => data,
+            None => return None,
+        }
+    };
+
+    let mut children = Vec::new();
+    if node.is_dir() {
+        let nodes: Vec<Node> = node.to_dir().borrow().nodes().values().cloned().collect();
+        for child in nodes.iter() {
+            if let Some(frozen) = freeze(fs, child) {
+                children.push(frozen);
+            }
+        }
+    }
+    let attr = if node.is_dir() { attr } else {
+        FileAttr { size: data.len(), blocks: data.blocks(), ..attr }
+    };
+    Some(Frozen { name: node.name(), attr: attr, data: data, children: children })
+}
+
+// Link a read-only copy of `frozen` into `parent_dir` as `name`
+pub fn publish(fs: &mut BasicFileSystem, parent_dir: &RcRef<Dir>, name: &str, frozen: &Frozen)
+    -> Result<()>
+{
+    let attr = FileAttr { ino: fs.alloc_inode(), ..frozen.attr };
+    let ops = FrozenOps::new(Rc::new(frozen.data.clone()));
+    if frozen.attr.kind != FileType::Directory {
+        return fs.link_node(parent_dir, Node::File(RcRef!(File::new(name, attr, None, ops))));
+    }
+    let dir = RcRef!(Dir::new(name, attr, None, ops));
+    try!(fs.link_node(parent_dir, Node::Dir(dir.clone())));
+    for child in frozen.children.iter() {
+        try!(publish(fs, &dir, &child.name, child));
+    }
+    Ok(())
+}
+
+// Put the live tree at the snapshot's source back into the state it was
+// frozen in. Nodes the snapshot does not cover (their ops do not freeze)
+// are left alone.
+pub fn rollback(fs: &mut BasicFileSystem, snapshot: &Snapshot) -> Result<()> {
+    let mut node = try!(fs.lookup_path(&snapshot.source).ok_or(Error::new(ENOENT)));
+    if node.attr().kind != snapshot.tree.attr.kind {
+        return Err(Error::new(EINVAL));
+    }
+    try!(rollback_node(fs, &mut node, &snapshot.tree));
+    info!("rollback: {:?} rolled back to {}", snapshot.source, snapshot.name);
+    Ok(())
+}
+
+fn rollback_node(fs: &mut BasicFileSystem, node: &mut Node, frozen: &Frozen) -> Result<()> {
+    let old = node.attr();
+    {
+        let _ops = node.ops();
+        let mut ops = _ops.borrow_mut();
+        try!(ops.thaw(fs, old.ino, frozen.data.clone()));
+    }
+    node.set_attr(FileAttr { ino: old.ino, kind: old.kind, nlink: old.nlink, ..frozen.attr });
+    if !node.is_dir() {
+        return Ok(());
+    }
+
+    // Covered children missing from the snapshot go away; directories
+    // it still has are rolled back in place
+    let dir = node.to_dir().clone();
+    let children: Vec<Node> = dir.borrow().nodes().values().cloned().collect();
+    for child in children.iter() {
+        let kept = child.is_dir() && frozen.children.iter()
+            .any(|f| f.name == child.name() && f.attr.kind == FileType::Directory);
+        if !kept && is_covered(fs, child) {
+            try!(remove_tree(fs, &dir, child));
+        }
+    }
+
+    for child in frozen.children.iter() {
+        let existing = dir.borrow().find_node(&child.name).cloned();
+        let mut node = match existing {
+            Some(node) => {
+                if !node.is_dir() || child.attr.kind != FileType::Directory {
+                    warn!("rollback: {} is in the way, skipped", child.name);
+                    continue;
+                }
+                node
+            },
+            None => {
+                let perm = child.attr.perm as u32;
+                if child.attr.kind == FileType::Directory {
+                    Node::Dir(try!(fs.mkdir(&dir, child.name.as_ref(), perm)))
+                } else {
+                    Node::File(try!(fs.mkfile(&dir, child.name.as_ref(), perm)))
+                }
+            },
+        };
+        try!(rollback_node(fs, &mut node, child));
+    }
+    Ok(())
+}
+
+// Bytes of the chunks only `frozen` still holds, freed with it
+pub fn unshared(frozen: &Frozen) -> u64 {
+    frozen.children.iter().fold(frozen.data.unshared(), |sum, child| sum + unshared(child))
+}
+
+fn is_covered(fs: &mut BasicFileSystem, node: &Node) -> bool {
+    let _ops = node.ops();
+    let mut ops = _ops.borrow_mut();
+    ops.freeze(fs, node.attr().ino).is_some()
+}
+
+// Remove the covered part of a subtree; a directory still holding
+// nodes of other ops stays
+fn remove_tree(fs: &mut BasicFileSystem, parent_dir: &RcRef<Dir>, node: &Node) -> Result<()> {
+    if node.is_dir() {
+        let dir = node.to_dir().clone();
+        let children: Vec<Node> = dir.borrow().nodes().values().cloned().collect();
+        for child in children.iter() {
+            if is_covered(fs, child) {
+                try!(remove_tree(fs, &dir, child));
+            }
+        }
+        if !dir.borrow().nodes().is_empty() {
+            warn!("rollback: {} holds nodes the snapshot does not cover, kept", node.name());
+            return Ok(());
+        }
+    }
+    fs.rmnod(parent_dir, node.name().as_ref(), node.attr().kind)
+}
+
+//
+// Frozen Operations
+//
+// Read-only nodes of a published snapshot. Snapshots live as long as
+// the mount and are not saved with the tree.
+//
+pub struct FrozenOps {
+    data: Rc<ChunkStore>,
+}
+
+impl FrozenOps {
+    pub fn new(data: Rc<ChunkStore>) -> RcRefBox<Operations> {
+        RcRefBox!(FrozenOps { data: data })
+    }
+
+    // For directories, which have no data
+    pub fn dir() -> RcRefBox<Operations> {
+        Self::new(Rc::new(ChunkStore::new()))
+    }
+}
+
+impl Operations for FrozenOps {
+    fn name(&self) -> &str {
+        "filesystem.FrozenOps"
+    }
+
+    fn new_ops(&self) -> RcRefBox<Operations> {
+        Self::dir()
+    }
+
+    fn entry_ttl(&self) -> Timespec { STATIC_TTL }
+    fn attr_ttl(&self) -> Timespec { STATIC_TTL }
+
+    fn setattr(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _set: &SetAttr) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn rename(&mut self, _fs: &mut BasicFileSystem, _ino: Inode,
+              _newparent: Inode, _newname: &str) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn mknod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _perm: Perm) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn rmnod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn truncate(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _size: u64) -> Result<()> {
+        Err(Error::new(EROFS))
+    }
+
+    fn persistent(&self) -> bool { false }
+
+    fn open(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, flags: Mode)
+        -> Result<RcRefBox<OpenHandler>>
+    {
+        if flags as c_int & ACCMODE != O_RDONLY {
+            return Err(Error::new(EROFS));
+        }
+        Ok(RcRefBox!(FrozenHandler { data: self.data.clone() }))
+    }
+}
+
+struct FrozenHandler {
+    data: Rc<ChunkStore>,
+}
+
+impl OpenHandler for FrozenHandler {
+    fn name(&self) -> &str {
+        "FrozenOps.FrozenHandler"
+    }
+
+    fn direct_io(&self) -> bool { false }
+    fn keep_cache(&self) -> bool { true }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        Ok(self.data.read(offset, size))
+    }
+
+    fn write(&mut self, _data: &[u8], _offset: u64, _size: u64) -> Result<u64> {
+        Err(Error::new(EBADF))
+    }
+}
+
+//
+// Snapshot Operations
+//
+// Provider for SNAPSHOT_DIR: keeps it read-only and adds a `ctl` file
+// taking one command per line:
+//
+//  take <name> <path>      freeze the subtree at <path> as <name>
+//  drop <name>             forget snapshot <name>
+//  rollback <name>         put <path> back into its frozen state
+//
+// Reading `ctl` lists the snapshots as "<name> <path> <taken>".
+//
+pub struct SnapshotOps;
+
+impl SnapshotOps {
+    pub fn new() -> RcRefBox<Operations> { RcRefBox!(SnapshotOps) }
+}
+
+impl Operations for SnapshotOps {
+    fn name(&self) -> &str {
+        "filesystem.SnapshotOps"
+    }
+
+    fn new_ops(&self) -> RcRefBox<Operations> {
+        FrozenOps::dir()
+    }
+
+    fn install(&mut self, fs: &mut BasicFileSystem) -> bool {
+        let dir = match fs.snapshot_dir() {
+            Ok(dir) => dir,
+            Err(_) => return false,
+        };
+        let (uid, gid) = fs.owner();
+        let attr = FileAttr { ino: fs.alloc_inode(), perm: 0o600, uid: uid, gid: gid, ..fileattr_new() };
+        let ctl = Node::File(RcRef!(File::new(CTL_NAME, attr, None, CtlOps::new(commands()))));
+        fs.link_node(&dir, ctl).is_ok()
+    }
+
+    fn uninstall(&mut self, fs: &mut BasicFileSystem) -> bool {
+        if let Ok(dir) = fs.snapshot_dir() {
+            let ctl = dir.borrow().find_node(CTL_NAME).cloned();
+            if let Some(ctl) = ctl {
+                fs.unlink_node(&dir, &ctl);
+            }
+        }
+        true
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::subtree(SNAPSHOT_DIR)]
+    }
+}
+
+fn commands() -> CtlCommands {
+    CtlCommands::new("SnapshotOps.CtlHandler")
+        .command("take <name> <path>", |fs, args| {
+            try!(fs.snapshot(args[1].as_ref(), args[0]));
+            Ok(None)
+        })
+        .command("drop <name>", |fs, args| {
+            try!(fs.drop_snapshot(args[0]));
+            Ok(None)
+        })
+        .command("rollback <name>", |fs, args| {
+            try!(fs.rollback(args[0]));
+            Ok(None)
+        })
+        .status(|fs| {
+            let mut listing = String::new();
+            for snapshot in fs.snapshots() {
+                listing.push_str(&format!("{} {} {}\n",
+                    snapshot.name, snapshot.source.display(), snapshot.taken.sec));
+            }
+            listing
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::libc::consts::os::posix88::*;
+    use common::Path;
+    use chunk::CHUNK_SIZE;
+    use core::BasicFileSystem;
+    use harness::Harness;
+    use harness::fixture::{TempDir, put};
+
+    #[test]
+    fn copies_are_charged() {
+        let mut h = Harness::new();
+        h.mknod("/f").unwrap();
+        put(&mut h, "/f", b"old");
+        let usage = h.fs().usage();
+        assert_eq!(usage.borrow().bytes(), CHUNK_SIZE);
+        h.fs().snapshot(Path::new("/"), "s").unwrap();
+        assert_eq!(usage.borrow().bytes(), CHUNK_SIZE);
+        put(&mut h, "/f", b"new");
+        assert_eq!(usage.borrow().bytes(), 2 * CHUNK_SIZE);
+        h.fs().drop_snapshot("s").unwrap();
+        assert_eq!(usage.borrow().bytes(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn rollback_needs_snapshot_file_with_journal() {
+        let dir = TempDir::new("rollback");
+        let mut fs = BasicFileSystem::new();
+        fs.set_journal_path(Some(dir.join("journal")));
+        let mut h = Harness::with_fs(fs);
+        h.mount().unwrap();
+        h.mknod("/f").unwrap();
+        h.fs().snapshot(Path::new("/"), "s").unwrap();
+        assert_eq!(h.fs().rollback("s").err().map(|err| err.errno()), Some(EPERM));
+    }
+}
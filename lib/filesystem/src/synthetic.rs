@@ -0,0 +1,224 @@
+extern crate libc;
+extern crate fuse;
+
+use std::mem;
+use std::rc::Rc;
+use std::cell::RefCell;
+use self::fuse::FileAttr;
+use self::libc::c_int;
+use self::libc::consts::os::posix88::*; /* POSIX errno, open flags */
+
+use fs::*;
+use common::*;
+use ops::{Operations, OpenHandler, ACCMODE};
+use core::BasicFileSystem;
+
+//
+// Synthetic files
+//
+// Files whose contents are generated rather than stored, built with
+//
+//  try!(fs.synthetic_file("/net/tcp/stats")
+//       .on_read(|fs| Ok(stats(fs).into_bytes()))
+//       .on_write(|fs, buf| reset(fs, buf))
+//       .create());
+//
+// on_read runs when the file is opened for reading and reads are served
+// from what it returned, so a reader sees one consistent copy whatever
+// its offsets. on_write is given the data of each write as it comes.
+// Missing parent directories are created. The file is not saved in
+// snapshots: whoever creates it does so again on install. It takes no
+// writes on a read-only filesystem unless built with
+// writable_when_read_only(), e.g. for a query file.
+//
+
+pub type Reader = Rc<Box<Fn(&mut BasicFileSystem) -> Result<Vec<u8>>>>;
+pub type Writer = Rc<Box<Fn(&mut BasicFileSystem, &[u8]) -> Result<()>>>;
+
+pub struct SyntheticFile<'a> {
+    fs: &'a mut BasicFileSystem,
+    path: PathBuf,
+    perm: Option<Perm>,
+    reader: Option<Reader>,
+    writer: Option<Writer>,
+    writable_when_read_only: bool,
+}
+
+impl<'a> SyntheticFile<'a> {
+    pub fn new(fs: &'a mut BasicFileSystem, path: &Path) -> SyntheticFile<'a> {
+        SyntheticFile {
+            fs: fs,
+            path: path.to_path_buf(),
+            perm: None,
+            reader: None,
+            writer: None,
+            writable_when_read_only: false,
+        }
+    }
+
+    pub fn on_read<F>(mut self, f: F) -> SyntheticFile<'a>
+        where F: Fn(&mut BasicFileSystem) -> Result<Vec<u8>> + 'static
+    {
+        self.reader = Some(Rc::new(Box::new(f)));
+        self
+    }
+
+    pub fn on_write<F>(mut self, f: F) -> SyntheticFile<'a>
+        where F: Fn(&mut BasicFileSystem, &[u8]) -> Result<()> + 'static
+    {
+        self.writer = Some(Rc::new(Box::new(f)));
+        self
+    }
+
+    // Defaults to 0444 with on_read, plus 0200 with on_write
+    pub fn perm(mut self, perm: Perm) -> SyntheticFile<'a> {
+        self.perm = Some(perm);
+        self
+    }
+
+    // Keep taking writes when the filesystem is read-only
+    pub fn writable_when_read_only(mut self) -> SyntheticFile<'a> {
+        self.writable_when_read_only = true;
+        self
+    }
+
+    pub fn create(self) -> Result<RcRef<File>> {
+        let SyntheticFile { fs, path, perm, reader, writer, writable_when_read_only } = self;
+        let name = try!(path.file_name().and_then(|name| name.to_str())
+                        .ok_or(Error::new(EINVAL).with_path(&path)));
+        let parent = try!(fs.mkdir_all(path.parent().unwrap(), 0o755)
+                          .map_err(|err| err.with_path(&path)));
+        if parent.borrow().find_node(name).is_some() {
+            return Err(Error::new(EEXIST).with_path(&path));
+        }
+
+        let mut default_perm = 0;
+        if reader.is_some() {
+            default_perm |= 0o444;
+        }
+        if writer.is_some() {
+            default_perm |= 0o200;
+        }
+        let perm = perm.unwrap_or(default_perm);
+        let (uid, gid) = fs.owner();
+        let attr = FileAttr { ino: fs.alloc_inode(), perm: perm, uid: uid, gid: gid, ..fileattr_new() };
+        let ops = SyntheticOps::new(reader, writer, writable_when_read_only);
+        let file = RcRef!(File::new(name, attr, None, ops));
+        try!(fs.link_node(&parent, Node::File(file.clone())).map_err(|err| err.with_path(&path)));
+        Ok(file)
+    }
+}
+
+struct SyntheticOps {
+    reader: Option<Reader>,
+    writer: Option<Writer>,
+    writable_when_read_only: bool,
+}
+
+impl SyntheticOps {
+    fn new(reader: Option<Reader>, writer: Option<Writer>, writable_when_read_only: bool)
+        -> RcRefBox<Operations>
+    {
+        RcRefBox!(SyntheticOps {
+            reader: reader,
+            writer: writer,
+            writable_when_read_only: writable_when_read_only,
+        })
+    }
+}
+
+impl Operations for SyntheticOps {
+    fn name(&self) -> &str {
+        "filesystem.SyntheticOps"
+    }
+
+    fn new_ops(&self) -> RcRefBox<Operations> {
+        Self::new(self.reader.clone(), self.writer.clone(), self.writable_when_read_only)
+    }
+
+    fn rmnod(&mut self, _fs: &mut BasicFileSystem, _ino: Inode) -> Result<()> {
+        Err(Error::new(EPERM))
+    }
+
+    fn persistent(&self) -> bool { false }
+
+    fn writable_when_read_only(&self) -> bool {
+        self.writable_when_read_only
+    }
+
+    fn open(&mut self, fs: &mut BasicFileSystem, _ino: Inode, flags: Mode)
+        -> Result<RcRefBox<OpenHandler>>
+    {
+        let mode = flags as c_int & ACCMODE;
+        let content = if mode != O_WRONLY {
+            let reader = try!(self.reader.as_ref().ok_or(Error::new(EACCES)));
+            try!((**reader)(fs))
+        } else {
+            Vec::new()
+        };
+        let writer = if mode != O_RDONLY {
+            Some(try!(self.writer.clone().ok_or(Error::new(EACCES))))
+        } else {
+            None
+        };
+        Ok(RcRefBox!(SyntheticHandler { content: content, writer: writer, pending: Vec::new() }))
+    }
+}
+
+struct SyntheticHandler {
+    content: Vec<u8>,       // as generated on open
+    writer: Option<Writer>,
+    pending: Vec<u8>,       // written, not yet given to the writer
+}
+
+impl OpenHandler for SyntheticHandler {
+    fn name(&self) -> &str {
+        "SyntheticOps.SyntheticHandler"
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        Ok(read_at(&self.content, offset, size))
+    }
+
+    fn write(&mut self, data: &[u8], _offset: u64, size: u64) -> Result<u64> {
+        if self.writer.is_none() {
+            return Err(Error::new(EBADF));
+        }
+        self.pending.extend(data.iter().cloned());
+        Ok(size)
+    }
+
+    fn commit(&mut self, fs: &mut BasicFileSystem) -> Result<()> {
+        let data = mem::replace(&mut self.pending, Vec::new());
+        match self.writer {
+            Some(ref writer) => (**writer)(fs, &data),
+            None => Ok(()),
+        }
+    }
+}
+
+// The part of `data` a read of `size` bytes at `offset` gets
+pub fn read_at(data: &[u8], offset: u64, size: u64) -> Vec<u8> {
+    let len = data.len() as u64;
+    let begin = if offset < len { offset } else { len };
+    let end = if begin + size < len { begin + size } else { len };
+    data[begin as usize .. end as usize].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::libc::consts::os::posix88::*;
+    use harness::Harness;
+
+    #[test]
+    fn writable_when_read_only() {
+        let mut h = Harness::new();
+        h.fs().synthetic_file("/w").on_write(|_, _| Ok(())).create().unwrap();
+        h.fs().synthetic_file("/q").on_write(|_, _| Ok(())).writable_when_read_only().create().unwrap();
+        h.fs().set_read_only(true);
+        assert_eq!(h.open("/w", O_WRONLY).err(), Some(EROFS));
+        let fh = h.open("/q", O_WRONLY).unwrap();
+        assert_eq!(h.write(fh, 0, b"x"), Ok(1));
+        h.release(fh).unwrap();
+    }
+}
@@ -11,4 +11,17 @@ extern crate log;
 #[macro_use]
 extern crate filesystem;
 
+use filesystem::ops::Operations;
+use filesystem::common::*;
+use filesystem::control::Provider;
+
 pub mod tcp;
+
+// Providers of the netfs crate, for BasicFileSystem::add_providers()
+pub static PROVIDERS: &'static [(&'static str, Provider)] = &[
+    ("netfs.tcp", tcp_provider as Provider),
+];
+
+fn tcp_provider(_args: &[&str]) -> Result<RcRefBox<Operations>> {
+    Ok(tcp::RootDirOps::new())
+}
@@ -10,13 +10,14 @@ use std::cell::RefCell;
 use std::net;
 use std::io::prelude::*;
 use self::fuse::{FileType};
-use self::libc::consts::os::posix88::*;
+use self::libc::consts::os::posix88::{EBUSY, ENOENT, ENOSYS};
 
 use self::filesystem::*;
 use self::filesystem::fs::*;
 use self::filesystem::ops::*;
 use self::filesystem::common::*;
 use self::filesystem::core::Priority;
+use self::filesystem::router::Route;
 
 pub struct RootDirOps;
 
@@ -31,16 +32,24 @@ impl ops::Operations for RootDirOps {
     fn new_ops(&self) -> RcRefBox<Operations> { Self::new() }
 
     fn install(&mut self, fs: &mut BasicFileSystem) -> bool {
-        fs.register_ops(Priority::max_value(), ClientOps::new());
-        fs.register_ops(Priority::max_value(), SessionDirOps::new());
-        fs.register_ops(Priority::max_value(), CloneOps::new());
-        true
+        fs.register_ops(Priority::max_value(), ClientOps::new()) &&
+        fs.register_ops(Priority::max_value(), SessionDirOps::new()) &&
+        fs.register_ops(Priority::max_value(), CloneOps::new())
     }
 
-    fn is_target(&mut self, path: &Path, kind: FileType) -> bool {
-        kind == FileType::Directory && path == Path::new("/net/tcp")
+    fn uninstall(&mut self, fs: &mut BasicFileSystem) -> bool {
+        fs.unregister_ops("netfs.tcp.CloneOps") &&
+        fs.unregister_ops("netfs.tcp.SessionDirOps") &&
+        fs.unregister_ops("netfs.tcp.ClientOps")
     }
 
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::glob("/net/tcp").kind(FileType::Directory)]
+    }
+
+    // Connections are made by creating files here, see ClientOps
+    fn writable_when_read_only(&self) -> bool { true }
+
     fn mknod(&mut self, fs: &mut BasicFileSystem, ino: Inode, _perm: Perm) -> Result<()> {
         let dir = fs.find_node(ino).unwrap().clone();
         try!(fs.mkfile(dir.to_dir(), "clone".as_ref(), 0o660));
@@ -63,11 +72,11 @@ impl Operations for CloneOps {
     fn name(&self) -> &str { "netfs.tcp.CloneOps" }
     fn new_ops(&self) -> RcRefBox<Operations> { Self::new() }
 
-    fn is_target(&mut self, path: &Path, kind: FileType) -> bool {
-        kind == FileType::RegularFile && path == Path::new("/net/tcp/clone")
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::glob("/net/tcp/clone").kind(FileType::RegularFile)]
     }
 
-    fn open(&mut self, fs: &mut BasicFileSystem, ino: Inode, _perm: Perm)
+    fn open(&mut self, fs: &mut BasicFileSystem, ino: Inode, _flags: Mode)
         -> Result<RcRefBox<OpenHandler>>
     {
         let tcp_dir = {
@@ -105,7 +114,7 @@ impl OpenHandler for CloneHandler {
         })
     }
 
-    fn write(&mut self, _src: &[u8], _offset: u64, _size: u64) -> Result<u64> { Err(ENOSYS) }
+    fn write(&mut self, _src: &[u8], _offset: u64, _size: u64) -> Result<u64> { Err(Error::new(ENOSYS)) }
 }
 
 
@@ -120,14 +129,17 @@ impl SessionDirOps {
     }
 }
 
-static SESSION_DIR_REG: regex::Regex = regex!(r"/net/tcp/(\d+)");
+static SESSION_DIR_REG: regex::Regex = regex!(r"^/net/tcp/(\d+)$");
 
 impl Operations for SessionDirOps {
     fn name(&self) -> &str { "netfs.tcp.SessionDirOps" }
     fn new_ops(&self) -> RcRefBox<Operations> { Self::new() }
-    fn is_target(&mut self, path: &Path, kind: FileType) -> bool {
-        kind == FileType::Directory && SESSION_DIR_REG.is_match(path.to_str().unwrap())
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::regex("/net/tcp", SESSION_DIR_REG.clone()).kind(FileType::Directory)]
     }
+
+    // Sessions do not outlive the process that opened clone
+    fn persistent(&self) -> bool { false }
 }
 
 
@@ -141,28 +153,43 @@ impl ClientOps {
     }
 }
 
-static CLIENT_OPS_REG: regex::Regex = regex!(r"/net/tcp/((\d{1,3}\.){3}\d{1,3}:\d{1,6})");
+static CLIENT_OPS_REG: regex::Regex = regex!(r"^/net/tcp/((\d{1,3}\.){3}\d{1,3}:\d{1,6})$");
 
 impl Operations for ClientOps {
     fn name(&self) -> &str { "netfs.tcp.ClientOps" }
     fn new_ops(&self) -> RcRefBox<Operations> { Self::new() }
 
-    fn is_target(&mut self, path: &Path, kind: FileType) -> bool {
-        kind == FileType::RegularFile && CLIENT_OPS_REG.is_match(path.to_str().unwrap())
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::regex("/net/tcp", CLIENT_OPS_REG.clone()).kind(FileType::RegularFile)]
     }
 
+    // Connecting and talking over the socket change nothing stored
+    fn writable_when_read_only(&self) -> bool { true }
+
     fn mknod(&mut self, fs: &mut BasicFileSystem, ino: Inode, _perm: Perm) -> Result<()> {
         let sockaddr: &str = &fs.find_node(ino).unwrap().name();
-        let socket = try!(net::TcpStream::connect(sockaddr).or(Err(ECONNREFUSED)));
+        let socket = try!(net::TcpStream::connect(sockaddr));
         self.socket = Some(socket);
         Ok(())
     }
 
-    fn open(&mut self, _fs: &mut BasicFileSystem, _ino: Inode, _perm: Perm)
+    // Reconnect to the saved address; the node is dropped if that fails
+    fn restore(&mut self, fs: &mut BasicFileSystem, ino: Inode, _data: &[u8]) -> Result<()> {
+        self.mknod(fs, ino, 0)
+    }
+
+    fn open(&mut self, fs: &mut BasicFileSystem, ino: Inode, _flags: Mode)
         -> Result<RcRefBox<OpenHandler>>
     {
-        let result = try!(self.socket.as_ref().ok_or(ENOENT).map(|s| s.try_clone()));
-        Ok( ClientHandler::open( try!(result.or(Err(ENOENT))) ) )
+        // A process holding a whole-file write lock owns the
+        // connection; only it can open the file again
+        if let Some(lock) = fs.locks().exclusive_lock(ino) {
+            if lock.pid != fs.opener_pid() {
+                return Err(Error::new(EBUSY));
+            }
+        }
+        let socket = try!(self.socket.as_ref().ok_or(Error::new(ENOENT)));
+        Ok( ClientHandler::open( try!(socket.try_clone()) ) )
     }
 }
 
@@ -182,10 +209,102 @@ impl OpenHandler for ClientHandler {
 
     fn read(&mut self, _offset: u64, _size: u64) -> Result<Vec<u8>> {
         let mut buf = Vec::new();
-        self.socket.read_to_end(&mut buf).and(Ok(buf)).or(Err(EIO))
+        try!(self.socket.read_to_end(&mut buf));
+        Ok(buf)
     }
 
     fn write(&mut self, src: &[u8], _offset: u64, size: u64) -> Result<u64> {
-        self.socket.write_all(src).and(Ok(size)).or(Err(EIO))
+        try!(self.socket.write_all(src));
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net;
+    use std::io::prelude::*;
+    use super::libc::consts::os::posix88::{O_RDONLY, O_RDWR, EBUSY, ENOENT, ENOSYS, EROFS};
+    use super::filesystem::lock;
+    use super::filesystem::harness::Harness;
+    use PROVIDERS;
+
+    fn mount_tcp() -> Harness {
+        let mut h = Harness::new();
+        h.fs().add_providers(PROVIDERS);
+        h.fs().load("netfs.tcp", 10, &[]).unwrap();
+        h.mkdir("/net").unwrap();
+        h.mkdir("/net/tcp").unwrap();
+        h
+    }
+
+    #[test]
+    fn clone_hands_out_sessions() {
+        let mut h = mount_tcp();
+        let first = h.open("/net/tcp/clone", O_RDONLY).unwrap();
+        let second = h.open("/net/tcp/clone", O_RDONLY).unwrap();
+        assert_eq!(h.read(first, 0, 32).unwrap(), b"0".to_vec());
+        assert_eq!(h.read(first, 1, 32).unwrap(), Vec::<u8>::new());
+        assert_eq!(h.read(second, 0, 32).unwrap(), b"1".to_vec());
+        assert_eq!(h.write(first, 0, b"x").err(), Some(ENOSYS));
+        assert!(h.lookup("/net/tcp/0").is_ok());
+        assert!(h.lookup("/net/tcp/1").is_ok());
+    }
+
+    #[test]
+    fn client_connects_on_mknod() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = format!("/net/tcp/{}", listener.local_addr().unwrap());
+        let mut h = mount_tcp();
+        h.mknod(&path).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        let fh = h.open(&path, O_RDWR).unwrap();
+        h.write(fh, 0, b"ping").unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(peer.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"ping");
+
+        peer.write_all(b"pong").unwrap();
+        drop(peer);
+        assert_eq!(h.read(fh, 0, 4096).unwrap(), b"pong".to_vec());
+    }
+
+    #[test]
+    fn client_connects_when_read_only() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = format!("/net/tcp/{}", listener.local_addr().unwrap());
+        let mut h = mount_tcp();
+        h.fs().set_read_only(true);
+        assert_eq!(h.mknod("/net/tcp/stored").err(), Some(EROFS));
+        h.mknod(&path).unwrap();
+        let fh = h.open(&path, O_RDWR).unwrap();
+        assert_eq!(h.write(fh, 0, b"ping"), Ok(4));
+    }
+
+    #[test]
+    fn exclusive_lock_holder_reopens() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = format!("/net/tcp/{}", listener.local_addr().unwrap());
+        let mut h = mount_tcp();
+        h.mknod(&path).unwrap();
+        let fh = h.open(&path, O_RDWR).unwrap();
+        h.flock(fh, lock::F_WRLCK).unwrap();
+
+        let again = h.open(&path, O_RDWR).unwrap();
+        h.release(again).unwrap();
+        h.set_pid(1);
+        assert_eq!(h.open(&path, O_RDWR).err(), Some(EBUSY));
+    }
+
+    #[test]
+    fn unload_takes_every_tcp_ops() {
+        let mut h = mount_tcp();
+        h.fs().unload("netfs.tcp.RootDirOps").unwrap();
+        assert!(h.fs().registered_ops().iter().all(|&(_, ref name)| !name.starts_with("netfs.")));
+        h.fs().load("netfs.tcp", 10, &[]).unwrap();
+        let tcp = h.fs().registered_ops().into_iter()
+            .filter(|&(_, ref name)| name.starts_with("netfs.")).count();
+        assert_eq!(tcp, 4);
+        assert_eq!(h.lookup("/net/tcp/nosuch").err(), Some(ENOENT));
     }
 }
@@ -0,0 +1,210 @@
+
+extern crate toml;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use self::toml::Value;
+
+use filesystem::core::Priority;
+
+//
+// Mount layout
+//
+//  fsname = "fuse-wlfs"
+//  allow_other = true                 # with default_permissions
+//  direct_io = false                  # for every file, else as each handle asks
+//  options = ["intr", "nonempty"]     # passed on to FUSE as is
+//  root_perm = "755"                  # octal
+//  read_only = false                  # EROFS except for opted-in files
+//  snapshot = "/var/lib/wlfs/tree"
+//  journal = "/var/lib/wlfs/journal"
+//
+//  [[provider]]
+//  name = "filesystem.passthrough"
+//  priority = 100                     # defaults to the highest
+//  prefix = "/srv"                    # first provider argument
+//  args = ["/srv"]                    # the rest
+//
+// Providers are loaded in the order listed.
+//
+
+pub const FS_NAME: &'static str = "fuse-wlfs";
+
+pub struct ProviderConfig {
+    pub name: String,
+    pub priority: Priority,
+    pub prefix: Option<String>,
+    pub args: Vec<String>,
+}
+
+impl ProviderConfig {
+    pub fn new(name: &str) -> ProviderConfig {
+        ProviderConfig {
+            name: name.to_owned(),
+            priority: Priority::max_value(),
+            prefix: None,
+            args: Vec::new(),
+        }
+    }
+
+    // What BasicFileSystem::load() is given
+    pub fn load_args(&self) -> Vec<&str> {
+        self.prefix.iter().chain(self.args.iter()).map(|arg| &arg[..]).collect()
+    }
+}
+
+pub struct Config {
+    pub fsname: String,
+    pub allow_other: bool,
+    pub direct_io: bool,
+    pub options: Vec<String>,
+    pub root_perm: u16,
+    pub read_only: bool,
+    pub snapshot: Option<PathBuf>,
+    pub journal: Option<PathBuf>,
+    pub providers: Vec<ProviderConfig>,
+}
+
+impl Config {
+    // The layout used without a config file
+    pub fn new() -> Config {
+        Config {
+            fsname: FS_NAME.to_owned(),
+            allow_other: true,
+            direct_io: false,
+            options: vec!["intr".to_owned(), "nonempty".to_owned()],
+            root_perm: 0o755,
+            read_only: false,
+            snapshot: None,
+            journal: None,
+            providers: vec![
+                ProviderConfig::new("netfs.tcp"),
+                ProviderConfig::new("filesystem.snapshot"),
+            ],
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+             .map_err(|err| format!("{}: {}", path.display(), err)));
+        Config::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut parser = toml::Parser::new(text);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let err = &parser.errors[0];
+                let (line, col) = parser.to_linecol(err.lo);
+                return Err(format!("{}:{}: {}", line + 1, col + 1, err.desc));
+            }
+        };
+
+        let mut config = Config::new();
+        config.providers.clear();
+        for (key, value) in table.iter() {
+            match &key[..] {
+                "fsname" => config.fsname = try!(string(key, value)),
+                "allow_other" => config.allow_other = try!(boolean(key, value)),
+                "direct_io" => config.direct_io = try!(boolean(key, value)),
+                "options" => config.options = try!(strings(key, value)),
+                "root_perm" => {
+                    let perm = try!(string(key, value));
+                    config.root_perm = try!(u16::from_str_radix(&perm, 8).ok()
+                        .and_then(|perm| if perm <= 0o7777 { Some(perm) } else { None })
+                        .ok_or(format!("{}: bad mode {:?}", key, perm)));
+                },
+                "read_only" => config.read_only = try!(boolean(key, value)),
+                "snapshot" => config.snapshot = Some(PathBuf::from(try!(string(key, value)))),
+                "journal" => config.journal = Some(PathBuf::from(try!(string(key, value)))),
+                "provider" => {
+                    let tables = match *value {
+                        Value::Array(ref tables) => tables,
+                        _ => return Err(format!("{}: expected [[provider]] tables", key)),
+                    };
+                    for table in tables.iter() {
+                        config.providers.push(try!(provider(table)));
+                    }
+                },
+                _ => return Err(format!("unknown key {:?}", key)),
+            }
+        }
+        Ok(config)
+    }
+
+    // Mount options for fuse::mount()
+    pub fn mount_options(&self) -> String {
+        let mut options = format!("-o,fsname={}", self.fsname);
+        // Other users are held to the mode bits, e.g. of /.wlfs/ctl,
+        // which the kernel only checks with default_permissions
+        if self.allow_other {
+            options.push_str(",allow_other,default_permissions");
+        }
+        for option in self.options.iter() {
+            options.push_str(&format!(",{}", option));
+        }
+        if self.direct_io {
+            options.push_str(",direct_io");
+        }
+        options
+    }
+}
+
+fn provider(value: &Value) -> Result<ProviderConfig, String> {
+    let table = match *value {
+        Value::Table(ref table) => table,
+        _ => return Err("provider: expected a table".to_owned()),
+    };
+    let name = match table.get("name") {
+        Some(name) => try!(string("provider.name", name)),
+        None => return Err("provider: missing name".to_owned()),
+    };
+    let mut provider = ProviderConfig::new(&name);
+    for (key, value) in table.iter() {
+        match &key[..] {
+            "name" => {},
+            "priority" => {
+                let priority = try!(integer(key, value));
+                if priority < 0 || priority > Priority::max_value() as i64 {
+                    return Err(format!("{}: priority {} out of range", name, priority));
+                }
+                provider.priority = priority as Priority;
+            },
+            "prefix" => provider.prefix = Some(try!(string(key, value))),
+            "args" => provider.args = try!(strings(key, value)),
+            _ => return Err(format!("{}: unknown key {:?}", name, key)),
+        }
+    }
+    Ok(provider)
+}
+
+fn string(key: &str, value: &Value) -> Result<String, String> {
+    match *value {
+        Value::String(ref s) => Ok(s.clone()),
+        _ => Err(format!("{}: expected a string", key)),
+    }
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, String> {
+    match *value {
+        Value::Boolean(b) => Ok(b),
+        _ => Err(format!("{}: expected true or false", key)),
+    }
+}
+
+fn integer(key: &str, value: &Value) -> Result<i64, String> {
+    match *value {
+        Value::Integer(n) => Ok(n),
+        _ => Err(format!("{}: expected an integer", key)),
+    }
+}
+
+fn strings(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    match *value {
+        Value::Array(ref values) => values.iter().map(|value| string(key, value)).collect(),
+        _ => Err(format!("{}: expected an array of strings", key)),
+    }
+}
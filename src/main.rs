@@ -2,36 +2,327 @@
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate getopts;
+extern crate libc;
 extern crate fuse;
 extern crate filesystem;
 extern crate netfs;
 
-use netfs::*;
+use std::io;
+use std::io::prelude::*;
+use std::env;
+use std::thread;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicIsize, ATOMIC_ISIZE_INIT, Ordering};
+use std::os::unix::io::{FromRawFd, AsRawFd};
+use libc::c_int;
+use libc::consts::os::posix88::{SIGINT, SIGTERM};
+use getopts::Options;
 use filesystem::core::Priority;
+use filesystem::control;
+use config::{Config, ProviderConfig};
 
-const FS_NAME: &'static str = "fuse-wlfs";
+mod config;
+
+const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+/* Exit codes, from sysexits.h */
+const EX_OK: i32 = 0;
+const EX_USAGE: i32 = 64;
+const EX_SOFTWARE: i32 = 70;
+const EX_OSERR: i32 = 71;
+const EX_CONFIG: i32 = 78;
+
+const LOG_LEVELS: &'static [&'static str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("f", "foreground", "stay in the foreground (default)");
+    opts.optflag("d", "daemon", "detach from the terminal once set up");
+    opts.optmulti("o", "", "extra FUSE mount options", "OPT[,OPT...]");
+    opts.optflag("r", "read-only", "mount read-only");
+    opts.optopt("c", "config", "TOML file with the mount layout", "FILE");
+    opts.optopt("l", "log-level", "off, error, warn, info, debug or trace", "LEVEL");
+    opts.optopt("u", "uid", "owner of the root and of created nodes (default: yours)", "UID");
+    opts.optopt("g", "gid", "group of the root and of created nodes (default: yours)", "GID");
+    opts.optopt("e", "enable", "load only these providers, e.g. tcp,snapshot", "NAME[,NAME...]");
+    opts.optflag("V", "version", "print the version and exit");
+    opts.optflag("h", "help", "print this help and exit");
+    opts
+}
+
+fn usage(program: &str, opts: &Options) -> String {
+    opts.usage(&format!("Usage: {} [options] mountpoint", program))
+}
+
+fn usage_error(program: &str, opts: &Options, err: &str) -> i32 {
+    let _ = writeln!(io::stderr(), "{}: {}\n\n{}", program, err, usage(program, opts));
+    EX_USAGE
+}
+
+// Keep the configured providers `names` refers to, by full name or by
+// the part after the last dot, and add those not configured
+fn enable(config: &mut Config, providers: &[&'static str], names: &str) -> Result<(), String> {
+    let mut enabled = Vec::new();
+    for name in names.split(',').filter(|name| !name.is_empty()) {
+        let suffix = format!(".{}", name);
+        match providers.iter().find(|p| **p == name || p.ends_with(&suffix)) {
+            Some(p) => enabled.push(*p),
+            None => return Err(format!("unknown provider {:?}", name)),
+        }
+    }
+    config.providers.retain(|p| enabled.contains(&&p.name[..]));
+    for name in enabled.into_iter() {
+        if !config.providers.iter().any(|p| p.name == name) {
+            config.providers.push(ProviderConfig::new(name));
+        }
+    }
+    Ok(())
+}
+
+extern {
+    fn signal(signum: c_int, handler: extern fn(c_int)) -> usize;
+    fn pipe(fds: *mut c_int) -> c_int;
+    fn write(fd: c_int, buf: *const u8, count: usize) -> isize;
+}
+
+/* Write end of the pipe on_signal() wakes the unmount thread with */
+static SIGNAL_PIPE: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+extern fn on_signal(signum: c_int) {
+    let byte = signum as u8;
+    unsafe { write(SIGNAL_PIPE.load(Ordering::SeqCst) as c_int, &byte, 1); }
+}
+
+// On SIGINT or SIGTERM, lazily unmount so that fuse::mount() returns and
+// the filesystem is dropped and torn down. A second signal, or a failed
+// unmount, exits right away.
+fn unmount_on_signal(mountpoint: &str) -> io::Result<()> {
+    let mut fds = [0 as c_int; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    SIGNAL_PIPE.store(fds[1] as isize, Ordering::SeqCst);
+    unsafe {
+        signal(SIGINT, on_signal);
+        signal(SIGTERM, on_signal);
+    }
+
+    let mountpoint = mountpoint.to_owned();
+    let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+    thread::spawn(move || {
+        let mut byte = [0u8; 1];
+        if signals.read(&mut byte).unwrap_or(0) == 0 {
+            return;
+        }
+        info!("signal {}: unmounting {}", byte[0], mountpoint);
+        let unmounted = Command::new("fusermount").arg("-u").arg("-z").arg(&mountpoint)
+            .status().map(|status| status.success()).unwrap_or(false);
+        if !unmounted {
+            error!("cannot unmount {}", mountpoint);
+            std::process::exit(EX_SOFTWARE);
+        }
+        if signals.read(&mut byte).unwrap_or(0) > 0 {
+            error!("signal {}: exiting before teardown", byte[0]);
+            std::process::exit(EX_SOFTWARE);
+        }
+    });
+    Ok(())
+}
+
+enum Detached {
+    Parent(i32),    // exit code the child reported
+    Child(File),    // where it reports it
+}
+
+// Fork, the child going on in a new session in /. The parent waits for
+// the child to write its exit code to the pipe, which it does once the
+// kernel has initialized the mount, or for it to exit without doing so.
+fn daemonize() -> io::Result<Detached> {
+    let mut fds = [0 as c_int; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut status, report) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(status);
+            unsafe { libc::setsid(); }
+            try!(env::set_current_dir("/"));
+            Ok(Detached::Child(report))
+        },
+        pid => {
+            drop(report);
+            let mut code = [0u8; 1];
+            if try!(status.read(&mut code)) == 1 {
+                return Ok(Detached::Parent(code[0] as i32));
+            }
+            let mut wstatus: c_int = 0;
+            if unsafe { libc::waitpid(pid, &mut wstatus, 0) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // WIFEXITED and WEXITSTATUS
+            Ok(Detached::Parent(if wstatus & 0x7f == 0 { (wstatus >> 8) & 0xff } else { EX_SOFTWARE }))
+        }
+    }
+}
+
+// Point stdin, stdout and stderr at /dev/null
+fn close_stdio() -> io::Result<()> {
+    let null = try!(OpenOptions::new().read(true).write(true).open("/dev/null"));
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
 
 fn wlfs_main(args: Vec<String>) -> i32 {
-    if args.len() < 2 {
-        println!("Usage: {} mountpoint", args[0]);
-        return -1;
+    let program = args[0].clone();
+    let opts = options();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(err) => return usage_error(&program, &opts, &err.to_string()),
+    };
+
+    if matches.opt_present("help") {
+        print!("{}", usage(&program, &opts));
+        return EX_OK;
+    }
+    if matches.opt_present("version") {
+        println!("wlfs {}", VERSION);
+        return EX_OK;
+    }
+    if matches.free.len() != 1 {
+        return usage_error(&program, &opts, "expected one mountpoint");
     }
+    if matches.opt_present("foreground") && matches.opt_present("daemon") {
+        return usage_error(&program, &opts, "--foreground and --daemon exclude each other");
+    }
+
+    if let Some(level) = matches.opt_str("log-level") {
+        if !LOG_LEVELS.contains(&&level[..]) {
+            return usage_error(&program, &opts, &format!("bad log level {:?}", level));
+        }
+        env::set_var("RUST_LOG", level);
+    }
+    env_logger::init().unwrap();
+
+    let mut owner = unsafe { [libc::getuid(), libc::getgid()] };
+    for (i, name) in ["uid", "gid"].iter().enumerate() {
+        if let Some(value) = matches.opt_str(name) {
+            match value.parse::<u32>() {
+                Ok(id) => owner[i] = id,
+                Err(_) => return usage_error(&program, &opts, &format!("bad {} {:?}", name, value)),
+            }
+        }
+    }
+
+    let mut config = match matches.opt_str("config") {
+        Some(path) => match Config::load(Path::new(&path)) {
+            Ok(config) => config,
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "{}: {}", program, err);
+                return EX_CONFIG;
+            }
+        },
+        None => Config::new(),
+    };
 
     let mut fs = filesystem::BasicFileSystem::new();
-    fs.register_ops(Priority::max_value(), tcp::RootDirOps::new());
+    fs.set_root_perm(config.root_perm);
+    fs.set_owner(owner[0], owner[1]);
+    fs.set_read_only(config.read_only || matches.opt_present("read-only"));
+    fs.add_providers(control::PROVIDERS);
+    fs.add_providers(netfs::PROVIDERS);
 
-    let options = format!(
-        "-o,fsname={},allow_other,\
-        intr,nonempty,direct_io", FS_NAME);
+    if let Some(names) = matches.opt_str("enable") {
+        if let Err(err) = enable(&mut config, &fs.providers(), &names) {
+            return usage_error(&program, &opts, &err);
+        }
+    }
+
+    fs.register_ops(Priority::max_value(), control::ControlOps::new());
+    for provider in config.providers.iter() {
+        if let Err(err) = fs.load(&provider.name, provider.priority, &provider.load_args()) {
+            let _ = writeln!(io::stderr(), "{}: cannot load {}: {}",
+                             program, provider.name, err);
+            return EX_CONFIG;
+        }
+    }
+
+    // Relative paths keep their meaning once a daemon has changed to /
+    let cwd = match env::current_dir() {
+        Ok(cwd) => cwd,
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "{}: current directory: {}", program, err);
+            return EX_OSERR;
+        }
+    };
+    let mountpoint = cwd.join(&matches.free[0]).to_str().unwrap().to_owned();
+    fs.set_snapshot_path(config.snapshot.as_ref().map(|path| cwd.join(path)));
+    fs.set_journal_path(config.journal.as_ref().map(|path| cwd.join(path)));
+
+    let mut options = config.mount_options();
+    for option in matches.opt_strs("o").iter() {
+        options.push_str(&format!(",{}", option));
+    }
 
     info!("mount options: {}", options);
 
-    fuse::mount(fs, &args[1], &[options.as_ref()]);
-    return 0;
+    // Errors before the mount is up still reach the terminal; the
+    // parent exits with the code the child reports
+    if matches.opt_present("daemon") {
+        let mut report = match daemonize() {
+            Ok(Detached::Parent(code)) => return code,
+            Ok(Detached::Child(report)) => report,
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "{}: cannot daemonize: {}", program, err);
+                return EX_OSERR;
+            }
+        };
+        fs.set_mount_report(move |result| {
+            let code = match *result {
+                Ok(_) => {
+                    if let Err(err) = close_stdio() {
+                        error!("cannot close stdio: {}", err);
+                    }
+                    EX_OK
+                },
+                Err(_) => EX_SOFTWARE,
+            };
+            let _ = report.write_all(&[code as u8]);
+        });
+    }
+
+    if let Err(err) = unmount_on_signal(&mountpoint) {
+        let _ = writeln!(io::stderr(), "{}: signal handling: {}", program, err);
+        return EX_OSERR;
+    }
+
+    let teardown = Arc::new(Mutex::new(Ok(())));
+    fs.set_teardown_report(teardown.clone());
+
+    if let Err(err) = fuse::mount(fs, &mountpoint, &[options.as_ref()]) {
+        error!("mount {}: {}", mountpoint, err);
+        let _ = writeln!(io::stderr(), "{}: mount {}: {}", program, mountpoint, err);
+        return EX_OSERR;
+    }
+    match *teardown.lock().unwrap() {
+        Ok(()) => EX_OK,
+        Err(ref err) => {
+            let _ = writeln!(io::stderr(), "{}: teardown failed: {}", program, err);
+            EX_SOFTWARE
+        }
+    }
 }
 
 fn main() {
-    env_logger::init().unwrap();
     let args = std::env::args().collect();
     let exit_code = wlfs_main(args);
     std::process::exit(exit_code);